@@ -0,0 +1,45 @@
+//! Assembles uBlock Origin's redirect resources and scriptlets into the
+//! resource JSON the browser and the web process extension load.
+//!
+//! Usage: `abrw-resources <uBlock checkout> <resources.json>`
+
+use adblock::resources::resource_assembler::{
+    assemble_scriptlet_resources, assemble_web_accessible_resources,
+};
+use std::path::PathBuf;
+use std::{env, fs, process};
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let (ublock, output) = match args.as_slice() {
+        [ublock, output] => (PathBuf::from(ublock), PathBuf::from(output)),
+        _ => {
+            eprintln!("Usage: abrw-resources <uBlock checkout> <resources.json>");
+            process::exit(2);
+        }
+    };
+
+    let mut resources = assemble_web_accessible_resources(
+        &ublock.join("src/web_accessible_resources"),
+        &ublock.join("src/js/redirect-resources.js"),
+    );
+    resources.extend(assemble_scriptlet_resources(
+        &ublock.join("assets/resources/scriptlets.js"),
+    ));
+
+    if resources.is_empty() {
+        eprintln!("No resources found in {}", ublock.display());
+        process::exit(1);
+    }
+
+    let json = serde_json::to_string(&resources).expect("Failed to serialize resources.");
+    if let Err(err) = fs::write(&output, json) {
+        eprintln!("Failed to write {}: {}", output.display(), err);
+        process::exit(1);
+    }
+    println!(
+        "Wrote {} resources to {}",
+        resources.len(),
+        output.display()
+    );
+}
@@ -1,76 +1,617 @@
-use adblock::{blocker::BlockerResult, request::Request, Engine};
-use reqwest::blocking;
+use crate::{
+    allowlist, https_only,
+    list_format::{self, ListFormat},
+    settings::Settings,
+    url_clean, utils,
+};
+use adblock::{
+    lists::{FilterSet, ParseOptions},
+    resources::Resource,
+    Engine,
+};
+use gtk::prelude::*;
+use rayon::prelude::*;
+use reqwest::{blocking, header, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use url::Url;
-use webkit2gtk::{URIRequestExt, WebViewExt};
-
-pub fn fetch_block_list(url: &str) -> Result<Vec<String>, Box<dyn Error>> {
-    println!("Fetching block lists");
-    let response = blocking::get(url)?;
-    let block_list = response
-        .text()?
-        .lines()
-        .map(|line| line.to_string())
-        .collect::<Vec<String>>();
-
-    Ok(block_list)
-}
-
-pub fn on_resource_load_started(
-    webview: &webkit2gtk::WebView,
-    _resource: &webkit2gtk::WebResource,
-    request: &webkit2gtk::URIRequest,
-    engine: &Engine,
-) {
-    if let Some(url_string) = request.uri() {
-        match Url::parse(&url_string) {
-            Ok(url) => {
-                let domain = match url.host_str() {
-                    Some(domain) => domain,
-                    None => {
-                        return;
-                    }
-                };
-
-                let request2 = Request::new(&url.to_string(), domain, "");
-                match request2 {
-                    Ok(req) => {
-                        let result = engine.check_network_request(&req);
-                        match result {
-                            BlockerResult {
-                                matched: true,
-                                important,
-                                redirect,
-                                rewritten_url,
-                                exception,
-                                filter,
-                            } => {
-                                if important {
-                                    println!(
-                                        "Request matched an important rule and should be blocked."
-                                    );
-                                } else {
-                                    println!("Request matched a non-important rule.");
-                                    if let Some(redirect_url) = redirect {
-                                        println!("Redirecting to: {}", redirect_url);
-                                    } else if let Some(rewritten_url) = rewritten_url {
-                                        println!("Rewritten URL: {}", rewritten_url);
-                                    } else if let Some(exception) = exception {
-                                        println!("Request is an exception: {}", exception);
-                                    } else if let Some(filter) = filter {
-                                        println!("Request matched filter: {}", filter);
-
-                                        webview.stop_loading()
-                                    }
-                                }
-                            }
-                            BlockerResult { matched: false, .. } => {}
-                        }
-                    }
-                    Err(err) => eprintln!("Error creating request: {}", err),
-                }
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, RwLock};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use webkit2gtk::{UserMessage, WebContext, WebContextExt};
+
+/// Lists younger than this are used from disk without asking the server.
+const LIST_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+/// Lists offered in the settings window out of the box, as (name, URL).
+pub const BUNDLED_LISTS: &[(&str, &str)] = &[
+    ("EasyList", "https://easylist.to/easylist/easylist.txt"),
+    (
+        "EasyList Cookie",
+        "https://ublockorigin.github.io/uAssets/thirdparties/easylist-cookies.txt",
+    ),
+    (
+        "uBlock Annoyances: Cookies",
+        "https://ublockorigin.github.io/uAssets/filters/annoyances-cookies.txt",
+    ),
+    (
+        "EasyList Newsletters",
+        "https://ublockorigin.github.io/uAssets/thirdparties/easylist-newsletters.txt",
+    ),
+    (
+        "uBlock Annoyances: Others",
+        "https://ublockorigin.github.io/uAssets/filters/annoyances-others.txt",
+    ),
+    (
+        "EasyList Social",
+        "https://ublockorigin.github.io/uAssets/thirdparties/easylist-social.txt",
+    ),
+    (
+        "EasyList Chat",
+        "https://ublockorigin.github.io/uAssets/thirdparties/easylist-chat.txt",
+    ),
+    (
+        "EasyList Annoyances",
+        "https://ublockorigin.github.io/uAssets/thirdparties/easylist-annoyances.txt",
+    ),
+    (
+        "StevenBlack hosts",
+        "https://raw.githubusercontent.com/StevenBlack/hosts/master/hosts",
+    ),
+];
+
+/// Always part of the engine, on top of the subscribed lists.
+const BUILTIN_RULES: &[&str] = &[
+    "-advertisement-icon.",
+    "-advertisement-management/",
+    "-advertisement.",
+    "-advertisement/script.",
+];
+
+/// The engine consulted by every tab. Replaced in place once a list update
+/// finishes, so tabs that are already open pick up the new rules immediately.
+#[derive(Clone)]
+pub struct SharedEngine(Arc<RwLock<Arc<Engine>>>);
+
+impl SharedEngine {
+    pub fn new(engine: Engine) -> Self {
+        SharedEngine(Arc::new(RwLock::new(Arc::new(engine))))
+    }
+
+    pub fn get(&self) -> Arc<Engine> {
+        Arc::clone(&self.0.read().unwrap())
+    }
+
+    pub fn swap(&self, engine: Engine) {
+        *self.0.write().unwrap() = Arc::new(engine);
+    }
+}
+
+/// Cache validators for one downloaded filter list. For local files
+/// `fetched_at` is the modification time instead.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ListMeta {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub fetched_at: u64,
+}
+
+/// What one list contributed to the last engine build.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListStats {
+    pub format: ListFormat,
+    /// Rules the engine accepted.
+    pub rules: usize,
+    /// Lines in a syntax the format converter can't translate.
+    pub unsupported: usize,
+    /// Converted rules adblock-rust failed to parse.
+    pub errors: usize,
+    /// Rules skipped because an earlier list already had them.
+    #[serde(default)]
+    pub duplicates: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+struct ListIndex {
+    lists: HashMap<String, ListMeta>,
+    /// Hash of the subscriptions and user rules the engine was built from.
+    config_hash: u64,
+    stats: HashMap<String, ListStats>,
+}
+
+pub fn cache_dir() -> PathBuf {
+    let dir = utils::data_dir().join("adblock");
+    fs::create_dir_all(dir.join("lists")).ok();
+    dir
+}
+
+fn index_path() -> PathBuf {
+    cache_dir().join("lists.json")
+}
+
+fn engine_path() -> PathBuf {
+    cache_dir().join("engine.dat")
+}
+
+/// Content-blocker JSON for WebKit, see `content_filter`.
+pub fn content_blocking_path() -> PathBuf {
+    cache_dir().join("content-blocking.json")
+}
+
+/// uBlock Origin scriptlets and redirect targets in adblock-rust's resource
+/// JSON, built by `abrw-resources` and installed by the PKGBUILD. A copy in
+/// the cache directory overrides the packaged one.
+fn resources_path() -> PathBuf {
+    let user_resources = cache_dir().join("resources.json");
+    if user_resources.exists() {
+        user_resources
+    } else {
+        PathBuf::from("/usr/share/abrw/resources.json")
+    }
+}
+
+fn load_resources() -> Vec<Resource> {
+    let path = resources_path();
+    let data = match fs::read_to_string(&path) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!(
+                "WARNING: adblock resources not loaded from {}: {}. Scriptlet (+js) and \
+                 $redirect rules will do nothing; build them with abrw-resources.",
+                path.display(),
+                err
+            );
+            return Vec::new();
+        }
+    };
+
+    serde_json::from_str(&data).unwrap_or_else(|err| {
+        eprintln!("Failed to parse adblock resources: {}", err);
+        Vec::new()
+    })
+}
+
+fn list_path(url: &str) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    url.hash(&mut hasher);
+    cache_dir()
+        .join("lists")
+        .join(format!("{:016x}.txt", hasher.finish()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn load_index() -> ListIndex {
+    fs::read_to_string(index_path())
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+fn save_index(index: &ListIndex) {
+    let json_data = serde_json::to_string(index).expect("Failed to serialize list index.");
+    if let Err(err) = fs::write(index_path(), json_data) {
+        eprintln!("Failed to write list index: {}", err);
+    }
+}
+
+/// Downloads `url`, sending the validators from `meta` so an unchanged list
+/// comes back as `Ok(None)` instead of being transferred again.
+pub fn fetch_block_list(
+    url: &str,
+    meta: Option<&ListMeta>,
+) -> Result<Option<(String, ListMeta)>, Box<dyn Error>> {
+    println!("Fetching block list {}", url);
+    let mut request = blocking::Client::new().get(url);
+
+    if let Some(meta) = meta {
+        if let Some(etag) = &meta.etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &meta.last_modified {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = request.send()?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+
+    let response = response.error_for_status()?;
+    let header_value = |name: header::HeaderName| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    };
+    let meta = ListMeta {
+        etag: header_value(header::ETAG),
+        last_modified: header_value(header::LAST_MODIFIED),
+        fetched_at: now(),
+    };
+
+    Ok(Some((response.text()?, meta)))
+}
+
+/// Reads a list from a local file. Counts as changed whenever the file was
+/// modified since the last build.
+fn local_block_list(
+    location: &str,
+    meta: Option<ListMeta>,
+) -> (Option<String>, Option<ListMeta>, bool) {
+    let path = location.strip_prefix("file://").unwrap_or(location);
+
+    let modified = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_secs())
+        .unwrap_or(0);
+
+    match fs::read_to_string(path) {
+        Ok(text) => {
+            let changed = meta.map_or(true, |meta| meta.fetched_at != modified);
+            let meta = ListMeta {
+                fetched_at: modified,
+                ..ListMeta::default()
+            };
+            (Some(text), Some(meta), changed)
+        }
+        Err(err) => {
+            eprintln!("Failed to read {}: {}", path, err);
+            (None, None, false)
+        }
+    }
+}
+
+/// Returns the list text to use for `url`, refreshing the cached copy when it
+/// is stale. Falls back to whatever is on disk if the network is unavailable.
+fn cached_block_list(
+    url: &str,
+    meta: Option<ListMeta>,
+) -> (Option<String>, Option<ListMeta>, bool) {
+    let path = list_path(url);
+    let cached = fs::read_to_string(&path).ok();
+
+    let meta = meta.filter(|_| cached.is_some());
+    if let Some(meta) = &meta {
+        if now().saturating_sub(meta.fetched_at) < LIST_MAX_AGE_SECS {
+            return (cached, Some(meta.clone()), false);
+        }
+    }
+
+    match fetch_block_list(url, meta.as_ref()) {
+        Ok(Some((text, meta))) => {
+            if let Err(err) = fs::write(&path, &text) {
+                eprintln!("Failed to cache {}: {}", url, err);
+            }
+            (Some(text), Some(meta), true)
+        }
+        Ok(None) => {
+            let meta = meta.map(|meta| ListMeta {
+                fetched_at: now(),
+                ..meta
+            });
+            (cached, meta, false)
+        }
+        Err(err) => {
+            eprintln!("Failed to fetch {}: {}", url, err);
+            (cached, meta, false)
+        }
+    }
+}
+
+fn is_remote(location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+/// Brings the enabled lists up to date and rebuilds the engine if any of them,
+/// or the subscriptions and user rules themselves, changed. Returns the new
+/// engine, which has also been written to disk.
+pub fn update_block_lists(settings: &Settings) -> Option<Engine> {
+    let mut index = load_index();
+
+    let locations = settings
+        .filter_lists
+        .iter()
+        .filter(|list| list.enabled)
+        .map(|list| list.location.as_str())
+        .collect::<Vec<_>>();
+
+    let mut hasher = DefaultHasher::new();
+    locations.hash(&mut hasher);
+    settings.user_filters.hash(&mut hasher);
+    let removeparam_rules = url_clean::builtin_rules();
+    removeparam_rules.hash(&mut hasher);
+    let config_hash = hasher.finish();
+
+    let started = Instant::now();
+    let results = locations
+        .par_iter()
+        .map(|location| {
+            let meta = index.lists.get(*location).cloned();
+            let (text, meta, changed) = if is_remote(location) {
+                cached_block_list(location, meta)
+            } else {
+                local_block_list(location, meta)
+            };
+            (location.to_string(), text, meta, changed)
+        })
+        .collect::<Vec<_>>();
+
+    let changed =
+        results.iter().any(|(_, _, _, changed)| *changed) || config_hash != index.config_hash;
+
+    println!(
+        "Checked {} filter lists in {:.2?}",
+        results.len(),
+        started.elapsed()
+    );
+
+    index.lists = results
+        .iter()
+        .filter_map(|(location, _, meta, _)| meta.clone().map(|meta| (location.clone(), meta)))
+        .collect();
+    index.config_hash = config_hash;
+    save_index(&index);
+
+    if !changed && engine_path().exists() {
+        return None;
+    }
+
+    // Format detection and conversion are independent per list; merging has
+    // to be sequential so duplicates are dropped in list order.
+    let started = Instant::now();
+    let converted = results
+        .par_iter()
+        .filter_map(|(location, text, _, _)| {
+            let text = text.as_ref()?;
+            let format = list_format::detect(text);
+            Some((location.clone(), format, list_format::convert(text, format)))
+        })
+        .collect::<Vec<_>>();
+    // The raw texts aren't needed anymore; free them before the engine build.
+    drop(results);
+
+    let mut filter_set = FilterSet::new(true);
+    let mut seen = HashSet::new();
+    for rule in BUILTIN_RULES
+        .iter()
+        .copied()
+        .chain(removeparam_rules.iter().map(String::as_str))
+        .chain(settings.user_filters.lines().map(str::trim))
+    {
+        if seen.insert(rule) {
+            filter_set.add_filter(rule, ParseOptions::default()).ok();
+        }
+    }
+
+    index.stats.clear();
+    for (location, format, converted) in &converted {
+        let stats = add_list(&mut filter_set, &mut seen, *format, converted);
+        println!(
+            "{}: {} list, {} rules, {} duplicates, {} unsupported, {} errors",
+            location,
+            stats.format.name(),
+            stats.rules,
+            stats.duplicates,
+            stats.unsupported,
+            stats.errors
+        );
+        index.stats.insert(location.clone(), stats);
+    }
+    save_index(&index);
+    println!(
+        "Parsed {} unique rules in {:.2?}",
+        seen.len(),
+        started.elapsed()
+    );
+
+    let started = Instant::now();
+    write_content_blocking_rules(filter_set.clone());
+    println!("Wrote content blocking rules in {:.2?}", started.elapsed());
+
+    let started = Instant::now();
+    let mut engine = Engine::from_filter_set(filter_set, true);
+    println!("Built adblock engine in {:.2?}", started.elapsed());
+
+    match engine.serialize_raw() {
+        Ok(data) => {
+            println!("Adblock engine is {} KiB", data.len() / 1024);
+            if let Err(err) = fs::write(engine_path(), data) {
+                eprintln!("Failed to write adblock engine: {}", err);
             }
-            Err(err) => eprintln!("Error parsing URL {}: {}", url_string, err),
         }
+        Err(err) => eprintln!("Failed to serialize adblock engine: {:?}", err),
+    }
+
+    // Resources are not part of the serialized engine.
+    engine.use_resources(load_resources());
+
+    Some(engine)
+}
+
+/// Adds the rules of one converted list that aren't in `seen` yet, counting
+/// what it contributed.
+fn add_list<'a>(
+    filter_set: &mut FilterSet,
+    seen: &mut HashSet<&'a str>,
+    format: ListFormat,
+    converted: &'a list_format::Converted,
+) -> ListStats {
+    let mut stats = ListStats {
+        format,
+        rules: 0,
+        unsupported: converted.unsupported,
+        errors: 0,
+        duplicates: 0,
+    };
+    for rule in &converted.rules {
+        if !seen.insert(rule.as_str()) {
+            stats.duplicates += 1;
+            continue;
+        }
+        match filter_set.add_filter(rule, ParseOptions::default()) {
+            Ok(()) => stats.rules += 1,
+            Err(_) => stats.errors += 1,
+        }
+    }
+    stats
+}
+
+/// Per-list results of the last engine build, keyed by location.
+pub fn list_stats() -> HashMap<String, ListStats> {
+    load_index().stats
+}
+
+/// Converts the lists to WebKit content-blocker JSON. Rules without an
+/// equivalent there are left to the web process extension.
+fn write_content_blocking_rules(filter_set: FilterSet) {
+    let rules = match filter_set.into_content_blocking() {
+        Ok((rules, _)) => rules,
+        Err(_) => {
+            eprintln!("Failed to convert filter lists to content blocking rules");
+            return;
+        }
+    };
+
+    let json_data = serde_json::to_string(&rules).expect("Failed to serialize content rules.");
+    if let Err(err) = fs::write(content_blocking_path(), json_data) {
+        eprintln!("Failed to write content blocking rules: {}", err);
+    }
+}
+
+/// Engine from the last successful list update, or an empty one on first run.
+pub fn load_cached_engine() -> Engine {
+    let mut engine = Engine::new(true);
+    if let Ok(data) = fs::read(engine_path()) {
+        if let Err(err) = engine.deserialize(&data) {
+            eprintln!("Failed to load cached adblock engine: {:?}", err);
+        }
+    }
+    engine.use_resources(load_resources());
+    engine
+}
+
+/// Handle to the background thread that refreshes the lists and rebuilds the
+/// engine. Requests made while an update is running are merged into one.
+#[derive(Clone)]
+pub struct ListUpdater(mpsc::Sender<()>);
+
+impl ListUpdater {
+    /// `on_update` is notified on the main loop whenever `engine` was swapped.
+    pub fn spawn(engine: SharedEngine, on_update: gtk::glib::Sender<()>) -> Self {
+        let (sender, receiver) = mpsc::channel::<()>();
+
+        thread::spawn(move || {
+            while receiver.recv().is_ok() {
+                while receiver.try_recv().is_ok() {}
+
+                if let Some(new_engine) = update_block_lists(&Settings::load()) {
+                    engine.swap(new_engine);
+                    on_update.send(()).expect("Failed to send");
+                }
+            }
+        });
+
+        ListUpdater(sender)
+    }
+
+    pub fn update(&self) {
+        self.0.send(()).ok();
+    }
+}
+
+/// Directory holding `libabrw_webext.so`: next to the executable during
+/// development, otherwise where the package installs it.
+fn web_extension_dir() -> PathBuf {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(PathBuf::from))
+        .filter(|dir| dir.join("libabrw_webext.so").exists())
+        .unwrap_or_else(|| PathBuf::from("/usr/lib/abrw"))
+}
+
+/// Initialization data of the extension: the files it reads and whether
+/// HTTPS-only mode is on.
+fn web_extension_data() -> gtk::glib::Variant {
+    (
+        engine_path().to_string_lossy().to_string(),
+        resources_path().to_string_lossy().to_string(),
+        allowlist::allowlist_path().to_string_lossy().to_string(),
+        url_clean::exceptions_path().to_string_lossy().to_string(),
+        https_only::exceptions_path().to_string_lossy().to_string(),
+        https_only::enabled(),
+    )
+        .to_variant()
+}
+
+/// Registers the web process extension that does the actual blocking. Must
+/// run before the first WebView is created.
+pub fn setup_web_extension(web_context: &WebContext) {
+    web_context.set_web_extensions_directory(&web_extension_dir().to_string_lossy());
+    web_context.set_web_extensions_initialization_user_data(&web_extension_data());
+    web_context.add_path_to_sandbox(cache_dir(), true);
+}
+
+/// Tells every web process to reload the engine from disk.
+pub fn notify_engine_updated(web_context: &WebContext) {
+    web_context.send_message_to_all_extensions(&UserMessage::new("abrw-engine-updated", None));
+}
+
+/// Tells every web process to reload the per-site settings that don't need a
+/// new engine, like the URL cleaning exceptions, and whether HTTPS-only mode
+/// is on. Web processes started later get the same state.
+pub fn notify_site_settings_updated(web_context: &WebContext) {
+    web_context.set_web_extensions_initialization_user_data(&web_extension_data());
+    web_context.send_message_to_all_extensions(&UserMessage::new(
+        "abrw-site-settings-updated",
+        Some(&https_only::enabled().to_variant()),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_list_stats() {
+        let hosts = list_format::convert(
+            "127.0.0.1 localhost\n0.0.0.0 ads.example.com # ads\n0.0.0.0 tracker.example.com\n255.255.255.255 broadcasthost",
+            ListFormat::Hosts,
+        );
+        let adblock = list_format::convert(
+            "! Title: Example\n||ads.example.com^\n||example.org^$no-such-option\n##.banner",
+            ListFormat::Adblock,
+        );
+
+        let mut filter_set = FilterSet::new(true);
+        let mut seen = HashSet::new();
+
+        let stats = add_list(&mut filter_set, &mut seen, ListFormat::Hosts, &hosts);
+        assert_eq!(stats.format, ListFormat::Hosts);
+        assert_eq!(stats.rules, 2);
+        assert_eq!(stats.unsupported, 1);
+        assert_eq!(stats.errors, 0);
+        assert_eq!(stats.duplicates, 0);
+
+        // `||ads.example.com^` came with the hosts list already.
+        let stats = add_list(&mut filter_set, &mut seen, ListFormat::Adblock, &adblock);
+        assert_eq!(stats.rules, 1);
+        assert_eq!(stats.unsupported, 0);
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.duplicates, 1);
     }
 }
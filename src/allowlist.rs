@@ -0,0 +1,26 @@
+//! Sites the user turned blocking off for.
+//!
+//! Stored as `@@||domain^$document` rules so the file is a valid filter list.
+//! It isn't compiled into the engine or the content blocker, so a toggle
+//! needs no rebuild: the web process extension reads the same file to skip
+//! every request made by an allowed page, tabs loading an allowed page go
+//! without the content filter, and cosmetic filtering checks it per page.
+
+use crate::adblock_abrw;
+use crate::domain_list::{DomainList, Format};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub fn allowlist_path() -> PathBuf {
+    adblock_abrw::cache_dir().join("allowlist.txt")
+}
+
+/// The current allowlist, for checking a page.
+pub fn cached() -> Arc<DomainList> {
+    DomainList::cached(allowlist_path(), Format::DocumentExceptions)
+}
+
+/// A copy of the allowlist to change and save.
+pub fn load() -> DomainList {
+    DomainList::load(allowlist_path(), Format::DocumentExceptions)
+}
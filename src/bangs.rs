@@ -0,0 +1,258 @@
+//! DuckDuckGo style `!bang` shortcuts, resolved without asking any server.
+//!
+//! A query with a `!trigger` word anywhere in it goes straight to the bang's
+//! site with the rest of the query. User bangs from the settings come first,
+//! so they can override the bundled ones.
+
+use crate::query::fill_template;
+use crate::settings::Settings;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Bang {
+    /// The word after `!`, without it.
+    pub trigger: String,
+    pub name: String,
+    /// Target URL with `{searchTerms}` where the query goes.
+    pub url: String,
+}
+
+/// Bangs shipped with abrw, as (trigger, name, URL).
+const BUNDLED_BANGS: &[(&str, &str, &str)] = &[
+    ("a", "Amazon", "https://www.amazon.com/s?k={searchTerms}"),
+    (
+        "arch",
+        "ArchWiki",
+        "https://wiki.archlinux.org/index.php?search={searchTerms}",
+    ),
+    (
+        "aur",
+        "AUR",
+        "https://aur.archlinux.org/packages?K={searchTerms}",
+    ),
+    ("b", "Bing", "https://www.bing.com/search?q={searchTerms}"),
+    (
+        "crates",
+        "crates.io",
+        "https://crates.io/search?q={searchTerms}",
+    ),
+    (
+        "ddg",
+        "DuckDuckGo",
+        "https://duckduckgo.com/?q={searchTerms}",
+    ),
+    (
+        "docs",
+        "Docs.rs",
+        "https://docs.rs/releases/search?query={searchTerms}",
+    ),
+    (
+        "e",
+        "eBay",
+        "https://www.ebay.com/sch/i.html?_nkw={searchTerms}",
+    ),
+    (
+        "g",
+        "Google",
+        "https://www.google.com/search?q={searchTerms}",
+    ),
+    ("gh", "GitHub", "https://github.com/search?q={searchTerms}"),
+    (
+        "gi",
+        "Google Images",
+        "https://www.google.com/search?tbm=isch&q={searchTerms}",
+    ),
+    (
+        "gm",
+        "Google Maps",
+        "https://www.google.com/maps/search/{searchTerms}",
+    ),
+    (
+        "gt",
+        "Google Translate",
+        "https://translate.google.com/?text={searchTerms}",
+    ),
+    (
+        "hn",
+        "Hacker News",
+        "https://hn.algolia.com/?q={searchTerms}",
+    ),
+    ("imdb", "IMDb", "https://www.imdb.com/find?q={searchTerms}"),
+    (
+        "mdn",
+        "MDN",
+        "https://developer.mozilla.org/search?q={searchTerms}",
+    ),
+    ("npm", "npm", "https://www.npmjs.com/search?q={searchTerms}"),
+    (
+        "osm",
+        "OpenStreetMap",
+        "https://www.openstreetmap.org/search?query={searchTerms}",
+    ),
+    ("pypi", "PyPI", "https://pypi.org/search/?q={searchTerms}"),
+    (
+        "r",
+        "Reddit",
+        "https://www.reddit.com/search/?q={searchTerms}",
+    ),
+    (
+        "rust",
+        "Rust std",
+        "https://doc.rust-lang.org/std/?search={searchTerms}",
+    ),
+    (
+        "so",
+        "Stack Overflow",
+        "https://stackoverflow.com/search?q={searchTerms}",
+    ),
+    (
+        "w",
+        "Wikipedia",
+        "https://en.wikipedia.org/wiki/Special:Search?search={searchTerms}",
+    ),
+    (
+        "wa",
+        "Wolfram Alpha",
+        "https://www.wolframalpha.com/input/?i={searchTerms}",
+    ),
+    (
+        "wt",
+        "Wiktionary",
+        "https://en.wiktionary.org/wiki/Special:Search?search={searchTerms}",
+    ),
+    (
+        "yt",
+        "YouTube",
+        "https://www.youtube.com/results?search_query={searchTerms}",
+    ),
+];
+
+fn bundled_bang(trigger: &str) -> Option<Bang> {
+    BUNDLED_BANGS
+        .iter()
+        .find(|(bundled, _, _)| *bundled == trigger)
+        .map(|(trigger, name, url)| Bang {
+            trigger: trigger.to_string(),
+            name: name.to_string(),
+            url: url.to_string(),
+        })
+}
+
+fn find_bang(user_bangs: &[Bang], trigger: &str) -> Option<Bang> {
+    user_bangs
+        .iter()
+        .find(|bang| bang.trigger == trigger)
+        .cloned()
+        .or_else(|| bundled_bang(trigger))
+}
+
+impl Bang {
+    fn url_for(&self, query: &str) -> String {
+        if query.is_empty() {
+            // `!gh` alone opens the site itself.
+            return url::Url::parse(&self.url)
+                .map(|url| url.origin().ascii_serialization() + "/")
+                .unwrap_or_else(|_| fill_template(&self.url, ""));
+        }
+        fill_template(&self.url, query)
+    }
+}
+
+/// The URL `text` leads to if it contains a known `!bang`.
+pub fn resolve(text: &str) -> Option<String> {
+    resolve_with(text, &Settings::load().bangs)
+}
+
+/// Like `resolve`, looking in `user_bangs` before the bundled bangs.
+fn resolve_with(text: &str, user_bangs: &[Bang]) -> Option<String> {
+    let words = text.split_whitespace().collect::<Vec<_>>();
+
+    let (index, bang) = words.iter().enumerate().find_map(|(index, word)| {
+        let trigger = word.strip_prefix('!')?.to_lowercase();
+        find_bang(user_bangs, &trigger).map(|bang| (index, bang))
+    })?;
+
+    let query = words
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != index)
+        .map(|(_, word)| *word)
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    Some(bang.url_for(&query))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bang(trigger: &str, url: &str) -> Bang {
+        Bang {
+            trigger: trigger.to_string(),
+            name: trigger.to_string(),
+            url: url.to_string(),
+        }
+    }
+
+    #[test]
+    fn resolves_bundled_bangs() {
+        let wikipedia = "https://en.wikipedia.org/wiki/Special:Search?search=";
+        let cases = [
+            ("!w foo", Some(format!("{}foo", wikipedia))),
+            ("foo !w", Some(format!("{}foo", wikipedia))),
+            ("foo !w bar", Some(format!("{}foo+bar", wikipedia))),
+            ("!W  foo   bar ", Some(format!("{}foo+bar", wikipedia))),
+            ("!w", Some("https://en.wikipedia.org/".to_string())),
+            (
+                "!gh rust-lang/rust",
+                Some("https://github.com/search?q=rust-lang%2Frust".to_string()),
+            ),
+            ("!w !g", Some(format!("{}%21g", wikipedia))),
+            ("!nosuchbang foo", None),
+            ("foo !nosuchbang", None),
+            (
+                "!nosuchbang foo !w",
+                Some(format!("{}%21nosuchbang+foo", wikipedia)),
+            ),
+            ("foo!w", None),
+            ("foo", None),
+            ("!", None),
+            ("", None),
+        ];
+
+        for (text, expected) in cases {
+            assert_eq!(resolve_with(text, &[]), expected, "text {:?}", text);
+        }
+    }
+
+    #[test]
+    fn user_bangs_override_bundled_ones() {
+        let user_bangs = [
+            bang(
+                "w",
+                "https://de.wikipedia.org/w/index.php?search={searchTerms}",
+            ),
+            bang("mine", "https://example.com/search?q={searchTerms}"),
+        ];
+        let cases = [
+            (
+                "!w foo",
+                Some("https://de.wikipedia.org/w/index.php?search=foo"),
+            ),
+            ("foo !mine", Some("https://example.com/search?q=foo")),
+            ("!mine", Some("https://example.com/")),
+            ("!g foo", Some("https://www.google.com/search?q=foo")),
+            ("!nosuchbang foo", None),
+        ];
+
+        for (text, expected) in cases {
+            assert_eq!(
+                resolve_with(text, &user_bangs).as_deref(),
+                expected,
+                "text {:?}",
+                text
+            );
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Pages bookmarked from the site settings popover.
+
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Bookmark {
+    pub url: String,
+    pub title: String,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Bookmarks {
+    bookmarks: Vec<Bookmark>,
+}
+
+pub fn bookmarks_path() -> PathBuf {
+    utils::data_dir().join("bookmarks.json")
+}
+
+impl Bookmarks {
+    pub fn load() -> Bookmarks {
+        let bookmarks = fs::read_to_string(bookmarks_path())
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default();
+
+        Bookmarks { bookmarks }
+    }
+
+    pub fn save(&self) {
+        let json_data =
+            serde_json::to_string(&self.bookmarks).expect("Failed to serialize bookmarks.");
+        if let Err(err) = fs::write(bookmarks_path(), json_data) {
+            eprintln!("Failed to write bookmarks: {}", err);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Bookmark> {
+        self.bookmarks.iter()
+    }
+
+    pub fn contains(&self, url: &str) -> bool {
+        self.bookmarks.iter().any(|bookmark| bookmark.url == url)
+    }
+
+    pub fn add(&mut self, url: &str, title: &str) {
+        if !self.contains(url) {
+            self.bookmarks.push(Bookmark {
+                url: url.to_string(),
+                title: title.to_string(),
+            });
+        }
+    }
+
+    pub fn remove(&mut self, url: &str) {
+        self.bookmarks.retain(|bookmark| bookmark.url != url);
+    }
+}
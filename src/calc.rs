@@ -0,0 +1,578 @@
+//! Arithmetic and unit conversions typed into the URL bar, answered locally.
+//!
+//! `2 * (3 + 4)`, `sqrt(2) ^ 3` and `12 km in miles` are answered here;
+//! anything else is left to the search engine. A bare number isn't a
+//! question, so an expression needs at least one operator or function.
+//! Dates like `2024-10-18` and `18/10/2024` and phone numbers like
+//! `555-123-4567` are searched for rather than calculated, while `7-3` and
+//! `10/2` still are; a leading `=` asks for any of them to be calculated.
+
+use std::f64::consts::{E, PI};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Answer {
+    /// The result as a plain number, copied on Enter.
+    pub value: String,
+    /// The result with its unit, if any.
+    pub display: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Operator(char),
+    Open,
+    Close,
+    Name(String),
+}
+
+fn tokenize(text: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_digit() || c == '.') {
+                    break;
+                }
+                number.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Number(number.parse().ok()?));
+        } else if c.is_alphabetic() {
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if !c.is_alphabetic() {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Name(name.to_lowercase()));
+        } else {
+            tokens.push(match c {
+                '+' | '-' | '*' | '/' | '%' | '^' => Token::Operator(c),
+                '×' => Token::Operator('*'),
+                '÷' => Token::Operator('/'),
+                '(' => Token::Open,
+                ')' => Token::Close,
+                _ => return None,
+            });
+            chars.next();
+        }
+    }
+
+    Some(tokens)
+}
+
+/// Recursive descent over the tokens:
+///
+/// ```text
+/// expression = term (("+" | "-") term)*
+/// term       = unary (("*" | "/" | "%") unary)*
+/// unary      = ("-" | "+") unary | power
+/// power      = primary ("^" unary)?
+/// primary    = number | constant | function "(" expression ")" | "(" expression ")"
+/// ```
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    /// Whether anything was computed, as opposed to a bare number.
+    operations: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat_operator(&mut self, operators: &[char]) -> Option<char> {
+        match self.peek() {
+            Some(Token::Operator(c)) if operators.contains(c) => {
+                let c = *c;
+                self.position += 1;
+                self.operations += 1;
+                Some(c)
+            }
+            _ => None,
+        }
+    }
+
+    fn expression(&mut self) -> Option<f64> {
+        let mut value = self.term()?;
+        while let Some(operator) = self.eat_operator(&['+', '-']) {
+            let rhs = self.term()?;
+            value = if operator == '+' {
+                value + rhs
+            } else {
+                value - rhs
+            };
+        }
+        Some(value)
+    }
+
+    fn term(&mut self) -> Option<f64> {
+        let mut value = self.unary()?;
+        while let Some(operator) = self.eat_operator(&['*', '/', '%']) {
+            let rhs = self.unary()?;
+            value = match operator {
+                '*' => value * rhs,
+                '/' => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Some(value)
+    }
+
+    fn unary(&mut self) -> Option<f64> {
+        match self.peek() {
+            Some(Token::Operator('-')) => {
+                self.position += 1;
+                Some(-self.unary()?)
+            }
+            Some(Token::Operator('+')) => {
+                self.position += 1;
+                self.unary()
+            }
+            _ => self.power(),
+        }
+    }
+
+    fn power(&mut self) -> Option<f64> {
+        let base = self.primary()?;
+        if self.eat_operator(&['^']).is_some() {
+            return Some(base.powf(self.unary()?));
+        }
+        Some(base)
+    }
+
+    fn primary(&mut self) -> Option<f64> {
+        match self.next()? {
+            Token::Number(value) => Some(value),
+            Token::Open => {
+                let value = self.expression()?;
+                (self.next()? == Token::Close).then_some(value)
+            }
+            Token::Name(name) => match name.as_str() {
+                "pi" | "π" => Some(PI),
+                "e" => Some(E),
+                _ => {
+                    let function: fn(f64) -> f64 = match name.as_str() {
+                        "sqrt" => f64::sqrt,
+                        "abs" => f64::abs,
+                        "ln" => f64::ln,
+                        "log" => f64::log10,
+                        "sin" => f64::sin,
+                        "cos" => f64::cos,
+                        "tan" => f64::tan,
+                        _ => return None,
+                    };
+                    if self.next()? != Token::Open {
+                        return None;
+                    }
+                    let argument = self.expression()?;
+                    if self.next()? != Token::Close {
+                        return None;
+                    }
+                    self.operations += 1;
+                    Some(function(argument))
+                }
+            },
+            _ => None,
+        }
+    }
+}
+
+/// The value of `text` and how many operations it took, if it is an
+/// arithmetic expression.
+fn evaluate_expression(text: &str) -> Option<(f64, usize)> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        position: 0,
+        operations: 0,
+    };
+
+    let value = parser.expression()?;
+    if parser.position != parser.tokens.len() || !value.is_finite() {
+        return None;
+    }
+    Some((value, parser.operations))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dimension {
+    Length,
+    Mass,
+    Volume,
+    Time,
+    Temperature,
+    Speed,
+    Data,
+}
+
+/// A unit as (names separated by `|`, dimension, factor, offset): a value in
+/// it is `value * factor + offset` in the dimension's base unit.
+type Unit = (&'static str, Dimension, f64, f64);
+
+const UNITS: &[Unit] = &[
+    (
+        "mm|millimeter|millimeters|millimetre|millimetres",
+        Dimension::Length,
+        0.001,
+        0.0,
+    ),
+    (
+        "cm|centimeter|centimeters|centimetre|centimetres",
+        Dimension::Length,
+        0.01,
+        0.0,
+    ),
+    ("m|meter|meters|metre|metres", Dimension::Length, 1.0, 0.0),
+    (
+        "km|kilometer|kilometers|kilometre|kilometres",
+        Dimension::Length,
+        1000.0,
+        0.0,
+    ),
+    ("in|inch|inches", Dimension::Length, 0.0254, 0.0),
+    ("ft|foot|feet", Dimension::Length, 0.3048, 0.0),
+    ("yd|yard|yards", Dimension::Length, 0.9144, 0.0),
+    ("mi|mile|miles", Dimension::Length, 1609.344, 0.0),
+    (
+        "nmi|nautical mile|nautical miles",
+        Dimension::Length,
+        1852.0,
+        0.0,
+    ),
+    ("mg|milligram|milligrams", Dimension::Mass, 0.001, 0.0),
+    ("g|gram|grams", Dimension::Mass, 1.0, 0.0),
+    (
+        "kg|kilogram|kilograms|kilo|kilos",
+        Dimension::Mass,
+        1000.0,
+        0.0,
+    ),
+    ("t|tonne|tonnes", Dimension::Mass, 1_000_000.0, 0.0),
+    ("oz|ounce|ounces", Dimension::Mass, 28.349523125, 0.0),
+    ("lb|lbs|pound|pounds", Dimension::Mass, 453.59237, 0.0),
+    ("st|stone|stones", Dimension::Mass, 6350.29318, 0.0),
+    (
+        "ml|milliliter|milliliters|millilitre|millilitres",
+        Dimension::Volume,
+        0.001,
+        0.0,
+    ),
+    ("l|liter|liters|litre|litres", Dimension::Volume, 1.0, 0.0),
+    (
+        "floz|fl oz|fluid ounce|fluid ounces",
+        Dimension::Volume,
+        0.0295735295625,
+        0.0,
+    ),
+    ("cup|cups", Dimension::Volume, 0.2365882365, 0.0),
+    ("pt|pint|pints", Dimension::Volume, 0.473176473, 0.0),
+    ("qt|quart|quarts", Dimension::Volume, 0.946352946, 0.0),
+    ("gal|gallon|gallons", Dimension::Volume, 3.785411784, 0.0),
+    ("ms|millisecond|milliseconds", Dimension::Time, 0.001, 0.0),
+    ("s|sec|second|seconds", Dimension::Time, 1.0, 0.0),
+    ("min|minute|minutes", Dimension::Time, 60.0, 0.0),
+    ("h|hr|hour|hours", Dimension::Time, 3600.0, 0.0),
+    ("d|day|days", Dimension::Time, 86400.0, 0.0),
+    ("wk|week|weeks", Dimension::Time, 604800.0, 0.0),
+    ("°c|c|celsius", Dimension::Temperature, 1.0, 273.15),
+    (
+        "°f|f|fahrenheit",
+        Dimension::Temperature,
+        5.0 / 9.0,
+        459.67 * 5.0 / 9.0,
+    ),
+    ("k|kelvin", Dimension::Temperature, 1.0, 0.0),
+    ("m/s", Dimension::Speed, 1.0, 0.0),
+    ("km/h|kph", Dimension::Speed, 1000.0 / 3600.0, 0.0),
+    ("mph", Dimension::Speed, 1609.344 / 3600.0, 0.0),
+    ("kn|knot|knots", Dimension::Speed, 1852.0 / 3600.0, 0.0),
+    ("b|byte|bytes", Dimension::Data, 1.0, 0.0),
+    ("kb|kilobyte|kilobytes", Dimension::Data, 1e3, 0.0),
+    ("mb|megabyte|megabytes", Dimension::Data, 1e6, 0.0),
+    ("gb|gigabyte|gigabytes", Dimension::Data, 1e9, 0.0),
+    ("tb|terabyte|terabytes", Dimension::Data, 1e12, 0.0),
+    ("kib|kibibyte|kibibytes", Dimension::Data, 1024.0, 0.0),
+    ("mib|mebibyte|mebibytes", Dimension::Data, 1048576.0, 0.0),
+    ("gib|gibibyte|gibibytes", Dimension::Data, 1073741824.0, 0.0),
+];
+
+fn find_unit(name: &str) -> Option<&'static Unit> {
+    let name = name.trim();
+    UNITS
+        .iter()
+        .find(|(names, _, _, _)| names.split('|').any(|unit| unit == name))
+}
+
+/// Splits `12 km` or `12km` into the expression and the unit after it.
+fn split_unit(text: &str) -> Option<(&str, &'static Unit)> {
+    // Leftmost word first, so `12 fl oz` isn't taken for `12 fl` and `oz`.
+    let (index, unit) = text
+        .char_indices()
+        .filter(|(index, _)| !text[..*index].ends_with(char::is_alphabetic))
+        .find_map(|(index, _)| find_unit(&text[index..]).map(|unit| (index, unit)))?;
+
+    Some((text[..index].trim(), unit))
+}
+
+/// `12 km in miles`, `70 f to c`, `1.5 gib as mb`.
+fn convert(text: &str) -> Option<Answer> {
+    let text = text.to_lowercase();
+    let (from, to) = [" in ", " to ", " as "]
+        .iter()
+        .filter_map(|separator| text.rsplit_once(separator))
+        .max_by_key(|(from, _)| from.len())?;
+
+    let (expression, from_unit) = split_unit(from.trim())?;
+    let to_unit = find_unit(to)?;
+    let (value, _) = evaluate_expression(expression)?;
+
+    let (_, from_dimension, from_factor, from_offset) = from_unit;
+    let (to_names, to_dimension, to_factor, to_offset) = to_unit;
+    if from_dimension != to_dimension {
+        return None;
+    }
+
+    let converted = (value * from_factor + from_offset - to_offset) / to_factor;
+    let value = format_number(converted);
+    Some(Answer {
+        display: format!("{} {}", value, to_names.split('|').next().unwrap_or(to)),
+        value,
+    })
+}
+
+/// Up to ten significant digits, without trailing zeros.
+fn format_number(value: f64) -> String {
+    if value == 0.0 {
+        return "0".to_string();
+    }
+    if value.abs() >= 1e15 || value.abs() < 1e-9 {
+        return format!("{:e}", value);
+    }
+
+    let decimals = (9 - value.abs().log10().floor() as i32).max(0) as usize;
+    let formatted = format!("{:.*}", decimals, value);
+    let formatted = if formatted.contains('.') {
+        formatted.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        &formatted
+    };
+
+    if formatted == "-0" {
+        "0".to_string()
+    } else {
+        formatted.to_string()
+    }
+}
+
+fn is_digits(text: &str, lengths: std::ops::RangeInclusive<usize>) -> bool {
+    lengths.contains(&text.len()) && text.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Whether `text` is `y-m-d`, `d/m/y` or `m/d/y`, with `-` or `/`.
+fn looks_like_date(text: &str) -> bool {
+    let separator = if text.contains('-') { '-' } else { '/' };
+    let parts = text.split(separator).collect::<Vec<_>>();
+    match parts[..] {
+        [year, month, day] if is_digits(year, 4..=4) => {
+            is_digits(month, 1..=2) && is_digits(day, 1..=2)
+        }
+        [first, second, year] => {
+            is_digits(first, 1..=2) && is_digits(second, 1..=2) && is_digits(year, 2..=4)
+        }
+        _ => false,
+    }
+}
+
+/// Whether `text` is a phone number: at least seven digits in groups, like
+/// `555-1234`, `(555) 123-4567` or `+1 555 123 4567`.
+fn looks_like_phone_number(text: &str) -> bool {
+    let text = text.strip_prefix('+').unwrap_or(text);
+    let digits = text.chars().filter(char::is_ascii_digit).count();
+    digits >= 7
+        && text
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '-' | ' ' | '(' | ')'))
+}
+
+/// The answer to `text` if it is a calculation or a unit conversion.
+pub fn evaluate(text: &str) -> Option<Answer> {
+    let text = text.trim();
+    let (text, forced) = match text.strip_prefix('=') {
+        Some(rest) => (rest.trim(), true),
+        None => (text, false),
+    };
+
+    if let Some(answer) = convert(text) {
+        return Some(answer);
+    }
+
+    if !forced && (looks_like_date(text) || looks_like_phone_number(text)) {
+        return None;
+    }
+
+    match evaluate_expression(text)? {
+        (_, 0) => None,
+        (value, _) => {
+            let value = format_number(value);
+            Some(Answer {
+                display: value.clone(),
+                value,
+            })
+        }
+    }
+}
+
+/// Puts `value` on the clipboard.
+pub fn copy(value: &str) {
+    gtk::Clipboard::get(&gtk::gdk::SELECTION_CLIPBOARD).set_text(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(text: &str) -> Option<String> {
+        evaluate(text).map(|answer| answer.display)
+    }
+
+    #[test]
+    fn evaluates_expressions() {
+        let cases = [
+            ("1 + 2 * 3", "7"),
+            ("(1 + 2) * 3", "9"),
+            ("10 - 4 - 3", "3"),
+            ("100 / 10 / 2", "5"),
+            ("10 % 3", "1"),
+            ("2^3^2", "512"),
+            ("-2^2", "-4"),
+            ("(-2)^2", "4"),
+            ("2 * -3", "-6"),
+            ("3 × 4 ÷ 2", "6"),
+            ("sqrt(16) + 1", "5"),
+            ("2 * pi", "6.283185307"),
+            ("1 / 3", "0.3333333333"),
+            ("0.1 + 0.2", "0.3"),
+            ("2 ^ 64", "1.8446744073709552e19"),
+        ];
+
+        for (text, expected) in cases {
+            assert_eq!(answer(text).as_deref(), Some(expected), "input {:?}", text);
+        }
+    }
+
+    #[test]
+    fn calculates_integers_that_are_not_dates() {
+        let cases = [
+            ("7-3", "4"),
+            ("10/2", "5"),
+            ("7/11", "0.6363636364"),
+            ("100-1", "99"),
+            ("2024-10", "2014"),
+            ("1/2/4", "0.125"),
+        ];
+
+        for (text, expected) in cases {
+            assert_eq!(answer(text).as_deref(), Some(expected), "input {:?}", text);
+        }
+    }
+
+    #[test]
+    fn converts_units() {
+        let cases = [
+            ("12 km in miles", "7.456454307 mi"),
+            ("12 fl oz in ml", "354.8823547 ml"),
+            ("12 in in cm", "30.48 cm"),
+            ("12in to cm", "30.48 cm"),
+            ("70 f to c", "21.11111111 °c"),
+            ("0 c in f", "32 °f"),
+            ("0 k to c", "-273.15 °c"),
+            ("100 km/h in mph", "62.13711922 mph"),
+            ("1 gib as mb", "1073.741824 mb"),
+            ("2 * 3 kg in lb", "13.22773573 lb"),
+        ];
+
+        for (text, expected) in cases {
+            assert_eq!(answer(text).as_deref(), Some(expected), "input {:?}", text);
+        }
+    }
+
+    #[test]
+    fn leaves_other_text_to_search() {
+        let cases = [
+            // Bare numbers and constants aren't questions.
+            "42",
+            "3.14",
+            "-5",
+            "pi",
+            "e",
+            "(7)",
+            // Dates and phone numbers.
+            "2024-10-18",
+            "2024/1/5",
+            "10/18/2024",
+            "18/10/2024",
+            "1-2-24",
+            "555-1234",
+            "555-123-4567",
+            "(555) 123-4567",
+            "+1 555 123 4567",
+            // Mismatched dimensions and unknown units.
+            "12 km in kg",
+            "70 f to mph",
+            "12 parsecs in km",
+            // Not arithmetic.
+            "rust",
+            "what is 2 + 2",
+            "foo(2)",
+            "1 / 0",
+            "2 +",
+            "(1 + 2",
+            "",
+        ];
+
+        for text in cases {
+            assert_eq!(answer(text), None, "input {:?}", text);
+        }
+    }
+
+    #[test]
+    fn equals_sign_forces_calculation() {
+        assert_eq!(answer("=7/11").as_deref(), Some("0.6363636364"));
+        assert_eq!(answer("= 2024-10-18").as_deref(), Some("1996"));
+        assert_eq!(answer("=42"), None);
+    }
+
+    #[test]
+    fn formats_numbers() {
+        let cases = [
+            (0.0, "0"),
+            (-0.0, "0"),
+            (1.5, "1.5"),
+            (100.0, "100"),
+            (1.0 / 3.0, "0.3333333333"),
+            (123456.789, "123456.789"),
+            (-2.5, "-2.5"),
+            (1e15, "1e15"),
+            (1e-10, "1e-10"),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(format_number(value), expected, "value {}", value);
+        }
+    }
+}
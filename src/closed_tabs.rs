@@ -0,0 +1,85 @@
+//! Tabs closed in this session, reopened with Ctrl+Shift+T or from the
+//! recently closed menu.
+
+use crate::tabs::{self, Browser};
+use gtk::{gdk, pango, prelude::*};
+use std::cell::RefCell;
+use std::rc::Rc;
+use webkit2gtk::WebViewSessionState;
+
+/// The oldest closed tabs are forgotten past this.
+const MAX_CLOSED_TABS: usize = 25;
+
+pub struct ClosedTab {
+    pub url: String,
+    pub title: String,
+    /// The back/forward list, so the reopened tab can still go back.
+    pub session: WebViewSessionState,
+    /// Where the tab was in the notebook.
+    pub position: u32,
+}
+
+/// Closed tabs, the most recently closed first.
+#[derive(Clone, Default)]
+pub struct ClosedTabs(Rc<RefCell<Vec<ClosedTab>>>);
+
+impl ClosedTabs {
+    pub fn push(&self, tab: ClosedTab) {
+        let mut tabs = self.0.borrow_mut();
+        tabs.insert(0, tab);
+        tabs.truncate(MAX_CLOSED_TABS);
+    }
+
+    /// Takes the tab at `index` off the list.
+    pub fn take(&self, index: usize) -> Option<ClosedTab> {
+        let mut tabs = self.0.borrow_mut();
+        (index < tabs.len()).then(|| tabs.remove(index))
+    }
+
+    /// Titles and URLs, in the order `take` indexes them.
+    fn entries(&self) -> Vec<(String, String)> {
+        self.0
+            .borrow()
+            .iter()
+            .map(|tab| (tab.title.clone(), tab.url.clone()))
+            .collect()
+    }
+}
+
+/// Pops up the recently closed menu under `button`.
+pub fn show_menu(browser: &Browser, button: &gtk::Button) {
+    let menu = gtk::Menu::new();
+    menu.set_attach_widget(Some(button));
+
+    let entries = browser.closed_tabs.entries();
+    if entries.is_empty() {
+        let item = gtk::MenuItem::with_label("No recently closed tabs");
+        item.set_sensitive(false);
+        menu.append(&item);
+    }
+
+    for (index, (title, url)) in entries.into_iter().enumerate() {
+        let label = gtk::Label::new(Some(&title));
+        label.set_xalign(0.0);
+        label.set_max_width_chars(50);
+        label.set_ellipsize(pango::EllipsizeMode::End);
+
+        let item = gtk::MenuItem::new();
+        item.add(&label);
+        item.set_tooltip_text(Some(&url));
+        item.connect_activate({
+            let browser = browser.clone();
+
+            move |_| tabs::reopen_closed_tab(&browser, index)
+        });
+        menu.append(&item);
+    }
+
+    menu.show_all();
+    menu.popup_at_widget(
+        button,
+        gdk::Gravity::SouthWest,
+        gdk::Gravity::NorthWest,
+        None,
+    );
+}
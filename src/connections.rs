@@ -1,9 +1,9 @@
 extern crate gtk;
 
-use crate::tabs;
-use adblock::FilterSet;
-use gtk::prelude::*;
-use std::sync::{Arc, Mutex};
+use crate::closed_tabs;
+use crate::settings::Settings;
+use crate::tabs::{self, Browser, TabOptions};
+use gtk::{gdk, glib::Propagation, prelude::*};
 use webkit2gtk::WebViewExt;
 
 pub fn notebook_switch_page(notebook: &gtk::Notebook, search_entry: &gtk::Entry) {
@@ -22,25 +22,46 @@ pub fn notebook_switch_page(notebook: &gtk::Notebook, search_entry: &gtk::Entry)
     });
 }
 
-pub fn new_tab_button_clicked(
-    new_tab_button: &gtk::Button,
-    notebook: &gtk::Notebook,
-    search_entry: &gtk::Entry,
-    filter_set: &Arc<Mutex<FilterSet>>,
-) {
+pub fn new_tab_button_clicked(new_tab_button: &gtk::Button, browser: &Browser) {
     new_tab_button.connect_clicked({
-        let notebook = notebook.clone();
-        let search_entry = search_entry.clone();
-        let filter_set = filter_set.clone();
+        let browser = browser.clone();
 
         move |_| {
+            let start_url = Settings::load().default_search_engine().home_url();
             tabs::add_webview_tab(
-                &notebook,
-                "https://start.duckduckgo.com/",
-                "New tab",
-                &search_entry,
-                &filter_set,
-            )
+                &browser,
+                TabOptions {
+                    url: Some(start_url),
+                    ..Default::default()
+                },
+            );
+        }
+    });
+}
+
+pub fn closed_tabs_button_clicked(closed_tabs_button: &gtk::Button, browser: &Browser) {
+    closed_tabs_button.connect_clicked({
+        let browser = browser.clone();
+
+        move |button| closed_tabs::show_menu(&browser, button)
+    });
+}
+
+pub fn window_key_press(window: &gtk::Window, browser: &Browser) {
+    window.connect_key_press_event({
+        let browser = browser.clone();
+
+        move |_, event| {
+            let modifiers = event.state() & gtk::accelerator_get_default_mod_mask();
+            let reopen = modifiers
+                == gdk::ModifierType::CONTROL_MASK | gdk::ModifierType::SHIFT_MASK
+                && event.keyval().to_upper() == gdk::keys::constants::T;
+
+            if reopen {
+                tabs::reopen_closed_tab(&browser, 0);
+                return Propagation::Stop;
+            }
+            Propagation::Proceed
         }
     });
 }
@@ -0,0 +1,263 @@
+//! Native content blocking through WebKit's `UserContentFilterStore`.
+//!
+//! The filter lists are converted to WebKit content-blocker JSON when the
+//! engine is rebuilt. WebKit compiles that JSON into bytecode that is kept on
+//! disk and evaluated inside the network process, so ordinary block rules need
+//! no round trip to Rust. The gtk-rs bindings skip these types, hence the
+//! small FFI wrapper below.
+//!
+//! The allowlist isn't compiled in: a tab loading an allowed site just goes
+//! without the filter, so toggling a site takes effect on the next load
+//! instead of after a rebuild of every list.
+
+use crate::{adblock_abrw, allowlist};
+use gtk::glib::{self, translate::*};
+use gtk::prelude::*;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::ptr::{self, NonNull};
+use std::rc::Rc;
+use url::Url;
+use webkit2gtk::{ffi, gio, LoadEvent, UserContentManagerExt, WebViewExt};
+
+/// Identifier of the compiled list filter inside the store.
+const FILTER_ID: &str = "abrw-adblock";
+
+/// A compiled content filter, ready to be added to a `UserContentManager`.
+pub struct UserContentFilter(NonNull<ffi::WebKitUserContentFilter>);
+
+impl Clone for UserContentFilter {
+    fn clone(&self) -> Self {
+        unsafe {
+            UserContentFilter(NonNull::new_unchecked(ffi::webkit_user_content_filter_ref(
+                self.0.as_ptr(),
+            )))
+        }
+    }
+}
+
+impl Drop for UserContentFilter {
+    fn drop(&mut self) {
+        unsafe { ffi::webkit_user_content_filter_unref(self.0.as_ptr()) }
+    }
+}
+
+type FilterCallback = Box<dyn FnOnce(Result<UserContentFilter, glib::Error>)>;
+
+unsafe fn filter_result(
+    filter: *mut ffi::WebKitUserContentFilter,
+    error: *mut glib::ffi::GError,
+) -> Result<UserContentFilter, glib::Error> {
+    match NonNull::new(filter) {
+        Some(filter) if error.is_null() => Ok(UserContentFilter(filter)),
+        _ => Err(from_glib_full(error)),
+    }
+}
+
+unsafe extern "C" fn save_trampoline(
+    store: *mut glib::gobject_ffi::GObject,
+    result: *mut gio::ffi::GAsyncResult,
+    user_data: glib::ffi::gpointer,
+) {
+    let mut error = ptr::null_mut();
+    let filter =
+        ffi::webkit_user_content_filter_store_save_finish(store as *mut _, result, &mut error);
+    let callback = Box::from_raw(user_data as *mut FilterCallback);
+    callback(filter_result(filter, error));
+}
+
+unsafe extern "C" fn load_trampoline(
+    store: *mut glib::gobject_ffi::GObject,
+    result: *mut gio::ffi::GAsyncResult,
+    user_data: glib::ffi::gpointer,
+) {
+    let mut error = ptr::null_mut();
+    let filter =
+        ffi::webkit_user_content_filter_store_load_finish(store as *mut _, result, &mut error);
+    let callback = Box::from_raw(user_data as *mut FilterCallback);
+    callback(filter_result(filter, error));
+}
+
+/// Where WebKit keeps compiled filters between runs.
+pub struct UserContentFilterStore(glib::Object);
+
+impl UserContentFilterStore {
+    pub fn new(path: &Path) -> Self {
+        unsafe {
+            let store = ffi::webkit_user_content_filter_store_new(path.to_glib_none().0);
+            UserContentFilterStore(from_glib_full(store as *mut glib::gobject_ffi::GObject))
+        }
+    }
+
+    fn as_ptr(&self) -> *mut ffi::WebKitUserContentFilterStore {
+        self.0.as_ptr() as *mut _
+    }
+
+    /// Compiles `source` and stores it under `identifier`, replacing any
+    /// previous filter with the same name.
+    pub fn save<F: FnOnce(Result<UserContentFilter, glib::Error>) + 'static>(
+        &self,
+        identifier: &str,
+        source: &[u8],
+        callback: F,
+    ) {
+        let source = glib::Bytes::from(source);
+        let callback: Box<FilterCallback> = Box::new(Box::new(callback));
+        unsafe {
+            ffi::webkit_user_content_filter_store_save(
+                self.as_ptr(),
+                identifier.to_glib_none().0,
+                source.to_glib_none().0,
+                ptr::null_mut(),
+                Some(save_trampoline),
+                Box::into_raw(callback) as *mut _,
+            );
+        }
+    }
+
+    /// Loads a filter compiled on a previous run.
+    pub fn load<F: FnOnce(Result<UserContentFilter, glib::Error>) + 'static>(
+        &self,
+        identifier: &str,
+        callback: F,
+    ) {
+        let callback: Box<FilterCallback> = Box::new(Box::new(callback));
+        unsafe {
+            ffi::webkit_user_content_filter_store_load(
+                self.as_ptr(),
+                identifier.to_glib_none().0,
+                ptr::null_mut(),
+                Some(load_trampoline),
+                Box::into_raw(callback) as *mut _,
+            );
+        }
+    }
+}
+
+/// The compiled list filter shared by all tabs.
+#[derive(Clone, Default)]
+pub struct ContentFilter {
+    /// `None` until it has been loaded or compiled.
+    filter: Rc<RefCell<Option<UserContentFilter>>>,
+    /// Tabs left without the filter, so every request reaches the web
+    /// process extension and shows up in the request log.
+    paused: Rc<RefCell<HashSet<webkit2gtk::WebView>>>,
+}
+
+/// Whether the page `webview` shows or is about to load is on the allowlist.
+fn is_allowed(webview: &webkit2gtk::WebView) -> bool {
+    webview
+        .uri()
+        .and_then(|uri| Url::parse(&uri).ok())
+        .and_then(|url| {
+            url.host_str()
+                .map(|host| allowlist::cached().contains(host))
+        })
+        .unwrap_or(false)
+}
+
+impl ContentFilter {
+    /// Adds the filter to `webview`, and keeps it off while the tab loads an
+    /// allowed site.
+    pub fn attach(&self, webview: &webkit2gtk::WebView) {
+        self.update(webview);
+
+        webview.connect_load_changed({
+            let content_filter = self.clone();
+
+            move |webview, event| {
+                // The URI is the one being loaded from here on, before any
+                // of its subresources are requested.
+                if matches!(event, LoadEvent::Started | LoadEvent::Redirected) {
+                    content_filter.update(webview);
+                }
+            }
+        });
+    }
+
+    /// Adds or removes the filter on `webview` for the page it loads.
+    fn update(&self, webview: &webkit2gtk::WebView) {
+        if self.paused.borrow().contains(webview) || is_allowed(webview) {
+            if let Some(manager) = webview.user_content_manager() {
+                manager.remove_all_filters();
+            }
+        } else if let Some(filter) = self.filter.borrow().as_ref() {
+            attach_filter(webview, filter);
+        }
+    }
+
+    /// Takes the filter off `webview` until `resume`. The extension's engine
+    /// has the same rules, so the page is blocked as before.
+    pub fn pause(&self, webview: &webkit2gtk::WebView) {
+        self.paused.borrow_mut().insert(webview.clone());
+        if let Some(manager) = webview.user_content_manager() {
+            manager.remove_all_filters();
+        }
+    }
+
+    pub fn resume(&self, webview: &webkit2gtk::WebView) {
+        self.paused.borrow_mut().remove(webview);
+        self.update(webview);
+    }
+
+    fn replace(&self, filter: UserContentFilter, notebook: &gtk::Notebook) {
+        *self.filter.borrow_mut() = Some(filter);
+
+        for page in 0..notebook.n_pages() {
+            if let Some(widget) = notebook.nth_page(Some(page)) {
+                if let Some(webview) = widget.downcast_ref::<webkit2gtk::WebView>() {
+                    self.update(webview);
+                }
+            }
+        }
+    }
+
+    /// Uses the filter compiled on a previous run, compiling it from the
+    /// cached JSON if WebKit has none stored yet.
+    pub fn load(&self, notebook: &gtk::Notebook) {
+        let content_filter = self.clone();
+        let notebook = notebook.clone();
+
+        store().load(FILTER_ID, move |result| match result {
+            Ok(filter) => content_filter.replace(filter, &notebook),
+            Err(_) => content_filter.compile(&notebook),
+        });
+    }
+
+    /// Recompiles the filter from the JSON written by the last list update.
+    pub fn compile(&self, notebook: &gtk::Notebook) {
+        let source = match fs::read(adblock_abrw::content_blocking_path()) {
+            Ok(source) => source,
+            Err(_) => return,
+        };
+
+        let content_filter = self.clone();
+        let notebook = notebook.clone();
+
+        store().save(FILTER_ID, &source, move |result| match result {
+            Ok(filter) => {
+                println!("Compiled content filter");
+                content_filter.replace(filter, &notebook)
+            }
+            Err(err) => eprintln!("Failed to compile content filter: {}", err),
+        });
+    }
+}
+
+fn store() -> UserContentFilterStore {
+    UserContentFilterStore::new(&adblock_abrw::cache_dir().join("content-filters"))
+}
+
+fn attach_filter(webview: &webkit2gtk::WebView, filter: &UserContentFilter) {
+    if let Some(manager) = webview.user_content_manager() {
+        manager.remove_all_filters();
+        unsafe {
+            ffi::webkit_user_content_manager_add_filter(
+                manager.to_glib_none().0,
+                filter.0.as_ptr(),
+            );
+        }
+    }
+}
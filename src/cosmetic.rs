@@ -0,0 +1,295 @@
+//! Element hiding from the `##` rules of the filter lists.
+//!
+//! Site specific selectors are looked up once the page commits. Generic
+//! class/id rules depend on what the page contains, so a small user script
+//! reports every new class and id it sees and the matching selectors are
+//! hidden as they show up.
+//!
+//! The view only knows the top-level URL, so site specific selectors are only
+//! applied to the top frame; frames from other sites would get the wrong
+//! site's rules otherwise, and same-site frames go without. Generic class/id
+//! selectors apply to every frame, with the top page's exceptions.
+//!
+//! `+js(...)` scriptlets have to run before any page script, so they are
+//! looked up when the main frame starts loading and registered as a document
+//! start user script for that host.
+//!
+//! Nothing is injected into sites on the allowlist.
+
+use crate::{adblock_abrw::SharedEngine, allowlist};
+use gtk::prelude::*;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use url::Url;
+use webkit2gtk::{
+    LoadEvent, UserContentInjectedFrames, UserContentManager, UserContentManagerExt, UserScript,
+    UserScriptInjectionTime, UserStyleLevel, UserStyleSheet, WebViewExt,
+};
+
+const MESSAGE_HANDLER: &str = "abrwCosmetic";
+
+const CLASS_ID_SCRIPT: &str = r#"
+(function () {
+    const handler = window.webkit && window.webkit.messageHandlers.abrwCosmetic;
+    if (!handler) {
+        return;
+    }
+
+    const seenClasses = new Set();
+    const seenIds = new Set();
+    let classes = [];
+    let ids = [];
+    let scheduled = false;
+
+    const flush = () => {
+        scheduled = false;
+        if (classes.length || ids.length) {
+            handler.postMessage(JSON.stringify({ classes, ids }));
+            classes = [];
+            ids = [];
+        }
+    };
+
+    const visit = (element) => {
+        if (element.id && !seenIds.has(element.id)) {
+            seenIds.add(element.id);
+            ids.push(element.id);
+        }
+        for (const name of element.classList) {
+            if (!seenClasses.has(name)) {
+                seenClasses.add(name);
+                classes.push(name);
+            }
+        }
+    };
+
+    const scan = (node, deep) => {
+        if (node.nodeType !== Node.ELEMENT_NODE) {
+            return;
+        }
+        visit(node);
+        if (deep) {
+            node.querySelectorAll("[id],[class]").forEach(visit);
+        }
+        if (!scheduled) {
+            scheduled = true;
+            setTimeout(flush, 50);
+        }
+    };
+
+    new MutationObserver((mutations) => {
+        for (const mutation of mutations) {
+            if (mutation.type === "attributes") {
+                scan(mutation.target, false);
+            } else {
+                mutation.addedNodes.forEach((node) => scan(node, true));
+            }
+        }
+    }).observe(document, {
+        childList: true,
+        subtree: true,
+        attributes: true,
+        attributeFilter: ["class", "id"],
+    });
+})();
+"#;
+
+#[derive(Deserialize)]
+struct ClassIdMessage {
+    classes: Vec<String>,
+    ids: Vec<String>,
+}
+
+/// Cosmetic state of the page currently shown in a tab.
+#[derive(Default)]
+struct PageState {
+    exceptions: HashSet<String>,
+    generichide: bool,
+}
+
+/// One rule per selector, so a selector WebKit doesn't understand only drops
+/// itself instead of the whole group.
+fn hide_css<'a>(selectors: impl IntoIterator<Item = &'a String>) -> String {
+    selectors
+        .into_iter()
+        .map(|selector| format!("{} {{ display: none !important; }}\n", selector))
+        .collect()
+}
+
+fn style_css(style_selectors: &HashMap<String, Vec<String>>) -> String {
+    style_selectors
+        .iter()
+        .flat_map(|(selector, styles)| {
+            styles
+                .iter()
+                .map(move |style| format!("{} {{ {} }}\n", selector, style))
+        })
+        .collect()
+}
+
+fn add_style_sheet(manager: &UserContentManager, css: &str, frames: UserContentInjectedFrames) {
+    if css.is_empty() {
+        return;
+    }
+
+    manager.add_style_sheet(&UserStyleSheet::new(
+        css,
+        frames,
+        UserStyleLevel::User,
+        &[],
+        &[],
+    ));
+}
+
+/// Document start script running the scriptlets for `uri`, limited to its
+/// host so it can't leak into a different site loaded later.
+fn scriptlet_script(engine: &SharedEngine, uri: &str) -> Option<UserScript> {
+    let url = Url::parse(uri).ok()?;
+    let host = url.host_str()?;
+    if allowlist::cached().contains(host) {
+        return None;
+    }
+
+    let script = engine.get().url_cosmetic_resources(uri).injected_script;
+    if script.is_empty() {
+        return None;
+    }
+
+    Some(UserScript::new(
+        &script,
+        UserContentInjectedFrames::TopFrame,
+        UserScriptInjectionTime::Start,
+        &[&format!("*://{}/*", host)],
+        &[],
+    ))
+}
+
+pub fn setup(webview: &webkit2gtk::WebView, engine: &SharedEngine) {
+    let manager = match webview.user_content_manager() {
+        Some(manager) => manager,
+        None => return,
+    };
+
+    let page = Rc::new(RefCell::new(PageState::default()));
+
+    manager.register_script_message_handler(MESSAGE_HANDLER);
+    manager.add_script(&UserScript::new(
+        CLASS_ID_SCRIPT,
+        UserContentInjectedFrames::AllFrames,
+        UserScriptInjectionTime::Start,
+        &[],
+        &[],
+    ));
+
+    let scriptlets: Rc<RefCell<Option<UserScript>>> = Rc::new(RefCell::new(None));
+
+    webview.connect_load_changed({
+        let engine = engine.clone();
+        let manager = manager.clone();
+
+        move |webview, event| {
+            // `load-changed` only reports the main frame, so frames navigating
+            // leave the top page's scriptlets alone. Don't move this to
+            // `decide-policy`: its navigation action can't tell a subframe
+            // from the main frame (`frame_name` is the target window name of
+            // new-window actions, `None` for unnamed iframes too).
+            if !matches!(event, LoadEvent::Started | LoadEvent::Redirected) {
+                return;
+            }
+
+            if let Some(old) = scriptlets.borrow_mut().take() {
+                manager.remove_script(&old);
+            }
+
+            // The URI being loaded, set before any of its content arrives.
+            if let Some(script) = webview
+                .uri()
+                .and_then(|uri| scriptlet_script(&engine, &uri))
+            {
+                manager.add_script(&script);
+                *scriptlets.borrow_mut() = Some(script);
+            }
+        }
+    });
+
+    webview.connect_load_changed({
+        let engine = engine.clone();
+        let manager = manager.clone();
+        let page = page.clone();
+
+        move |webview, event| {
+            if event != LoadEvent::Committed {
+                return;
+            }
+
+            let uri = match webview.uri() {
+                Some(uri) => uri,
+                None => return,
+            };
+
+            manager.remove_all_style_sheets();
+
+            let allowed = Url::parse(&uri)
+                .ok()
+                .and_then(|url| {
+                    url.host_str()
+                        .map(|host| allowlist::cached().contains(host))
+                })
+                .unwrap_or(false);
+            if allowed {
+                // Also turns off the class/id based hiding below.
+                *page.borrow_mut() = PageState {
+                    generichide: true,
+                    ..PageState::default()
+                };
+                return;
+            }
+
+            let resources = engine.get().url_cosmetic_resources(&uri);
+
+            // Looked up for the top-level URL, so wrong for other frames.
+            let frames = UserContentInjectedFrames::TopFrame;
+            add_style_sheet(&manager, &hide_css(&resources.hide_selectors), frames);
+            add_style_sheet(&manager, &style_css(&resources.style_selectors), frames);
+
+            *page.borrow_mut() = PageState {
+                exceptions: resources.exceptions,
+                generichide: resources.generichide,
+            };
+        }
+    });
+
+    let engine = engine.clone();
+    manager.connect_script_message_received(Some(MESSAGE_HANDLER), move |manager, result| {
+        let page = page.borrow();
+        if page.generichide {
+            return;
+        }
+
+        let message = match result.js_value() {
+            Some(value) => value.to_string(),
+            None => return,
+        };
+
+        let message: ClassIdMessage = match serde_json::from_str(&message) {
+            Ok(message) => message,
+            Err(err) => {
+                eprintln!("Invalid cosmetic message: {}", err);
+                return;
+            }
+        };
+
+        let selectors = engine.get().hidden_class_id_selectors(
+            &message.classes,
+            &message.ids,
+            &page.exceptions,
+        );
+        add_style_sheet(
+            manager,
+            &hide_css(&selectors),
+            UserContentInjectedFrames::AllFrames,
+        );
+    });
+}
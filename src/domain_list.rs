@@ -0,0 +1,140 @@
+//! Per-site settings stored as a list of domains: the allowlist and the
+//! HTTPS-only and URL cleaning exceptions.
+//!
+//! The web process extension reads the same files, so the formats have to
+//! stay this simple. Every navigation checks some of these lists, so they are
+//! read from disk once and kept in memory; saving a list replaces the copy.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+/// Lists as last read or saved, by path. The engine is built on another
+/// thread, so this is shared between threads.
+static CACHED: RwLock<Option<HashMap<PathBuf, Arc<DomainList>>>> = RwLock::new(None);
+
+/// The domain a site toggle applies to: the host without a leading `www.`.
+pub fn site_domain(host: &str) -> &str {
+    host.strip_prefix("www.").unwrap_or(host)
+}
+
+/// `host` followed by each of its parent domains.
+pub fn parent_domains(host: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(host), |domain| {
+        domain.split_once('.').map(|(_, parent)| parent)
+    })
+}
+
+/// How a list file writes its domains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One domain per line.
+    Domains,
+    /// `@@||domain^$document` rules, so the file is a valid filter list too.
+    DocumentExceptions,
+}
+
+impl Format {
+    fn parse(self, line: &str) -> Option<&str> {
+        let line = line.trim();
+        match self {
+            Format::Domains => Some(line).filter(|line| !line.is_empty()),
+            Format::DocumentExceptions => line.strip_prefix("@@||")?.strip_suffix("^$document"),
+        }
+    }
+
+    fn line(self, domain: &str) -> String {
+        match self {
+            Format::Domains => domain.to_string(),
+            Format::DocumentExceptions => format!("@@||{}^$document", domain),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DomainList {
+    path: PathBuf,
+    format: Format,
+    domains: BTreeSet<String>,
+}
+
+impl DomainList {
+    /// The list at `path`, read from disk only the first time.
+    pub fn cached(path: PathBuf, format: Format) -> Arc<DomainList> {
+        if let Some(list) = CACHED
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|cached| cached.get(&path))
+        {
+            return list.clone();
+        }
+
+        let list = Arc::new(DomainList::read(path, format));
+        CACHED
+            .write()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .insert(list.path.clone(), list.clone());
+        list
+    }
+
+    /// A copy of the list at `path` to change and `save`.
+    pub fn load(path: PathBuf, format: Format) -> DomainList {
+        DomainList::clone(&DomainList::cached(path, format))
+    }
+
+    fn read(path: PathBuf, format: Format) -> DomainList {
+        let domains = fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| format.parse(line))
+            .map(str::to_string)
+            .collect();
+
+        DomainList {
+            path,
+            format,
+            domains,
+        }
+    }
+
+    pub fn save(&self) {
+        let mut data = self.lines().join("\n");
+        data.push('\n');
+        if let Err(err) = fs::write(&self.path, data) {
+            eprintln!("Failed to write {}: {}", self.path.display(), err);
+        }
+
+        CACHED
+            .write()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .insert(self.path.clone(), Arc::new(self.clone()));
+    }
+
+    /// The lines of the file, which for document exceptions are filter rules.
+    pub fn lines(&self) -> Vec<String> {
+        self.domains
+            .iter()
+            .map(|domain| self.format.line(domain))
+            .collect()
+    }
+
+    /// Whether `host` or one of its parent domains is listed.
+    pub fn contains(&self, host: &str) -> bool {
+        parent_domains(host).any(|domain| self.domains.contains(domain))
+    }
+
+    pub fn add(&mut self, domain: &str) {
+        self.domains.insert(domain.to_string());
+    }
+
+    /// Removes `host` and whichever of its parent domains is listed.
+    pub fn remove(&mut self, host: &str) {
+        for domain in parent_domains(host) {
+            self.domains.remove(domain);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Pages visited, kept for the URL bar's suggestions.
+
+use crate::{settings::Settings, utils};
+use gtk::glib::{self, SourceId};
+use serde::{Deserialize, Serialize};
+use std::cell::{Ref, RefCell};
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use webkit2gtk::{LoadEvent, WebViewExt};
+
+/// The least recently visited pages are dropped past this.
+const MAX_ENTRIES: usize = 5000;
+
+/// How long after a visit the history is written, so a burst of page loads
+/// is saved once.
+const SAVE_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    pub url: String,
+    pub title: String,
+    pub visits: u32,
+    /// Seconds since the Unix epoch.
+    pub last_visit: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct History {
+    entries: Vec<HistoryEntry>,
+}
+
+pub fn history_path() -> PathBuf {
+    utils::data_dir().join("history.json")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl History {
+    pub fn load() -> History {
+        let entries = fs::read_to_string(history_path())
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default();
+
+        History { entries }
+    }
+
+    pub fn save(&self) {
+        let json_data = serde_json::to_string(&self.entries).expect("Failed to serialize history.");
+        if let Err(err) = fs::write(history_path(), json_data) {
+            eprintln!("Failed to write history: {}", err);
+        }
+    }
+
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    pub fn record(&mut self, url: &str, title: &str) {
+        match self.entries.iter_mut().find(|entry| entry.url == url) {
+            Some(entry) => {
+                entry.visits += 1;
+                entry.last_visit = now();
+                if !title.is_empty() {
+                    entry.title = title.to_string();
+                }
+            }
+            None => self.entries.push(HistoryEntry {
+                url: url.to_string(),
+                title: title.to_string(),
+                visits: 1,
+                last_visit: now(),
+            }),
+        }
+
+        if self.entries.len() > MAX_ENTRIES {
+            self.entries.sort_by(|a, b| b.last_visit.cmp(&a.last_visit));
+            self.entries.truncate(MAX_ENTRIES);
+        }
+    }
+}
+
+/// The history of this session, shared by the tabs recording to it and the
+/// URL bar suggesting from it. It is read from disk once and written back
+/// shortly after it changes.
+#[derive(Clone)]
+pub struct SharedHistory {
+    history: Rc<RefCell<History>>,
+    pending_save: Rc<RefCell<Option<SourceId>>>,
+}
+
+impl SharedHistory {
+    pub fn load() -> SharedHistory {
+        SharedHistory {
+            history: Rc::new(RefCell::new(History::load())),
+            pending_save: Default::default(),
+        }
+    }
+
+    pub fn borrow(&self) -> Ref<'_, History> {
+        self.history.borrow()
+    }
+
+    pub fn record(&self, url: &str, title: &str) {
+        self.history.borrow_mut().record(url, title);
+
+        if self.pending_save.borrow().is_some() {
+            return;
+        }
+        let source = glib::timeout_add_local_once(SAVE_DELAY, {
+            let shared = self.clone();
+
+            move || {
+                shared.pending_save.take();
+                shared.history.borrow().save();
+            }
+        });
+        self.pending_save.replace(Some(source));
+    }
+
+    /// Writes visits not saved yet, when the browser quits.
+    pub fn flush(&self) {
+        if let Some(source) = self.pending_save.take() {
+            source.remove();
+            self.history.borrow().save();
+        }
+    }
+}
+
+/// Records every page `webview` finishes loading, unless browsing privately.
+pub fn setup(webview: &webkit2gtk::WebView, history: &SharedHistory) {
+    let history = history.clone();
+    webview.connect_load_changed(move |webview, event| {
+        if event != LoadEvent::Finished || Settings::load().private_browsing {
+            return;
+        }
+
+        let uri = match webview.uri() {
+            Some(uri) if uri.starts_with("http://") || uri.starts_with("https://") => uri,
+            _ => return,
+        };
+        let title = webview.title().map(|s| s.to_string()).unwrap_or_default();
+
+        history.record(&uri, &title);
+    });
+}
@@ -0,0 +1,262 @@
+//! HTTPS-only mode.
+//!
+//! The web process extension rewrites `http://` requests to `https://` and
+//! reports each upgraded page load to the view. If the upgraded page then
+//! fails to load, the tab shows an interstitial offering to continue over
+//! plain HTTP, which adds the site to the exceptions.
+//!
+//! The continue link carries a one-time token, and is only followed from the
+//! interstitial it was put on, so other pages can't link to it to turn
+//! HTTPS-only mode off for a site.
+
+use crate::{
+    adblock_abrw,
+    domain_list::{self, DomainList, Format},
+    settings::Settings,
+};
+use gtk::glib;
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
+use url::Url;
+use webkit2gtk::{
+    NavigationPolicyDecision, NavigationPolicyDecisionExt, PolicyDecisionExt, PolicyDecisionType,
+    URIRequestExt, UserMessageExt, WebContext, WebViewExt,
+};
+
+/// Sent by the extension with the original URL of an upgraded page load.
+const UPGRADED_MESSAGE: &str = "abrw-https-upgraded";
+
+/// Link on the interstitial, handled in the tab's policy decisions.
+const CONTINUE_URI: &str = "abrw:https-continue";
+
+const INTERSTITIAL_HTML: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <title>Secure connection unavailable</title>
+    <style>
+        body {
+            font-family: Arial, sans-serif;
+            display: flex;
+            flex-direction: column;
+            align-items: center;
+            justify-content: center;
+            height: 100vh;
+            margin: 0;
+            background-color: #36393F;
+            color: #DCDDDE;
+        }
+        .container {
+            max-width: 600px;
+        }
+        a {
+            color: white;
+        }
+    </style>
+</head>
+<body>
+    <div class="container">
+        <h1>Secure connection unavailable</h1>
+        <p>{host} could not be loaded over HTTPS: {error}</p>
+        <p>Continuing over HTTP lets others on the network see and change what
+        you send to and receive from this site.</p>
+        <p><a href="{continue}">Continue to {url} over HTTP</a></p>
+    </div>
+</body>
+</html>
+"#;
+
+pub fn exceptions_path() -> PathBuf {
+    adblock_abrw::cache_dir().join("https-exceptions.txt")
+}
+
+/// Sites loaded over HTTP even in HTTPS-only mode.
+pub fn exceptions() -> DomainList {
+    DomainList::load(exceptions_path(), Format::Domains)
+}
+
+/// The interstitial a tab is showing.
+struct Interstitial {
+    /// The page the interstitial stands in for, which the tab shows as its
+    /// URI.
+    failing_uri: String,
+    http_url: Url,
+    token: String,
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn interstitial_html(http_url: &Url, token: &str, error: &str) -> String {
+    let mut continue_url = Url::parse(CONTINUE_URI).unwrap();
+    continue_url.query_pairs_mut().append_pair("token", token);
+
+    INTERSTITIAL_HTML
+        .replace("{host}", &escape_html(http_url.host_str().unwrap_or("")))
+        .replace("{error}", &escape_html(error))
+        .replace("{continue}", &escape_html(continue_url.as_str()))
+        .replace("{url}", &escape_html(http_url.as_str()))
+}
+
+/// Shows the interstitial if `failing_uri` is the upgrade of the last page
+/// load the extension reported. Returns `true` if it did.
+fn show_interstitial(
+    webview: &webkit2gtk::WebView,
+    upgraded: &RefCell<Option<Url>>,
+    shown: &RefCell<Option<Interstitial>>,
+    failing_uri: &str,
+    error: &str,
+) -> bool {
+    let http_url = match upgraded.borrow_mut().take() {
+        Some(http_url) => http_url,
+        None => return false,
+    };
+
+    let failing_url = match Url::parse(failing_uri) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+    if failing_url.scheme() != "https" || failing_url.host_str() != http_url.host_str() {
+        return false;
+    }
+
+    let token = glib::uuid_string_random().to_string();
+    let html = interstitial_html(&http_url, &token, error);
+    *shown.borrow_mut() = Some(Interstitial {
+        failing_uri: failing_uri.to_string(),
+        http_url,
+        token,
+    });
+
+    webview.load_alternate_html(&html, failing_uri, None);
+    true
+}
+
+/// Handles the interstitial's "continue over HTTP" link by adding an
+/// exception for the site and loading it. Returns `true` if `uri` was a
+/// continue link, followed or not.
+fn handle_continue_link(
+    webview: &webkit2gtk::WebView,
+    shown: &RefCell<Option<Interstitial>>,
+    uri: &str,
+) -> bool {
+    if !uri.starts_with(CONTINUE_URI) {
+        return false;
+    }
+
+    let token = Url::parse(uri).ok().and_then(|url| {
+        url.query_pairs()
+            .find(|(name, _)| name == "token")
+            .map(|(_, value)| value.to_string())
+    });
+
+    // The link is good once, and only on the interstitial showing it. The
+    // interstitial has no frames, so this also means the main frame followed
+    // it.
+    let interstitial = match shown.borrow_mut().take() {
+        Some(interstitial)
+            if Some(&interstitial.token) == token.as_ref()
+                && webview.uri().as_deref() == Some(interstitial.failing_uri.as_str()) =>
+        {
+            interstitial
+        }
+        _ => {
+            eprintln!("Ignored an HTTPS-only continue link not from its interstitial");
+            return true;
+        }
+    };
+
+    let http_url = interstitial.http_url;
+    if let Some(host) = http_url.host_str() {
+        let mut exceptions = exceptions();
+        exceptions.add(domain_list::site_domain(host));
+        exceptions.save();
+        adblock_abrw::notify_site_settings_updated(&WebContext::default().unwrap());
+    }
+
+    webview.load_uri(http_url.as_str());
+    true
+}
+
+pub fn setup(webview: &webkit2gtk::WebView) {
+    let upgraded: Rc<RefCell<Option<Url>>> = Rc::new(RefCell::new(None));
+    let shown: Rc<RefCell<Option<Interstitial>>> = Rc::new(RefCell::new(None));
+
+    webview.connect_decide_policy({
+        let shown = shown.clone();
+
+        move |webview, decision, decision_type| {
+            if decision_type != PolicyDecisionType::NavigationAction {
+                return false;
+            }
+
+            // The navigation action doesn't say which frame is navigating
+            // (`frame_name` is the target window name of new-window actions,
+            // `None` for unnamed iframes too), so don't filter on it. A
+            // continue link is only followed while the interstitial, which
+            // has no frames, is the tab's page, so it came from the main
+            // frame; see `handle_continue_link`.
+            let uri = match decision
+                .downcast_ref::<NavigationPolicyDecision>()
+                .and_then(|decision| decision.navigation_action())
+                .and_then(|action| action.request())
+                .and_then(|request| request.uri())
+            {
+                Some(uri) => uri,
+                None => return false,
+            };
+
+            if handle_continue_link(webview, &shown, &uri) {
+                decision.ignore();
+                return true;
+            }
+            false
+        }
+    });
+
+    webview.connect_user_message_received({
+        let upgraded = upgraded.clone();
+
+        move |_, message| {
+            if message.name().as_deref() != Some(UPGRADED_MESSAGE) {
+                return false;
+            }
+
+            *upgraded.borrow_mut() = message
+                .parameters()
+                .and_then(|parameters| parameters.get::<String>())
+                .and_then(|url| Url::parse(&url).ok());
+            true
+        }
+    });
+
+    webview.connect_load_failed({
+        let upgraded = upgraded.clone();
+        let shown = shown.clone();
+
+        move |webview, _, failing_uri, error| {
+            show_interstitial(webview, &upgraded, &shown, failing_uri, &error.to_string())
+        }
+    });
+
+    webview.connect_load_failed_with_tls_errors(move |webview, failing_uri, _, _| {
+        show_interstitial(
+            webview,
+            &upgraded,
+            &shown,
+            failing_uri,
+            "the certificate is not valid",
+        )
+    });
+}
+
+/// Whether the extensions should upgrade requests, sent along with the site
+/// settings.
+pub fn enabled() -> bool {
+    Settings::load().https_only
+}
@@ -0,0 +1,338 @@
+//! Filter list formats and their conversion to adblock network rules.
+//!
+//! Subscriptions come as ABP/uBO lists, AdGuard lists, hosts files or plain
+//! one-domain-per-line lists. The format is guessed from the header and the
+//! first rules, then each line is turned into something `FilterSet` parses.
+
+use serde::{Deserialize, Serialize};
+
+/// Rules looked at to guess a list without a recognizable header.
+const SAMPLE_LINES: usize = 200;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListFormat {
+    Adblock,
+    AdGuard,
+    Hosts,
+    Domains,
+}
+
+impl ListFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            ListFormat::Adblock => "Adblock",
+            ListFormat::AdGuard => "AdGuard",
+            ListFormat::Hosts => "hosts",
+            ListFormat::Domains => "domains",
+        }
+    }
+}
+
+/// Addresses hosts files point blocked names at.
+const BLACKHOLE_ADDRESSES: &[&str] = &["0.0.0.0", "127.0.0.1", "::", "::1"];
+
+/// Names every hosts file maps to itself, which must not be blocked.
+const LOCAL_HOSTNAMES: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+    "0.0.0.0",
+];
+
+/// AdGuard-only syntax adblock-rust doesn't implement: JS and CSS injection,
+/// HTML filtering and their exceptions.
+const ADGUARD_ONLY_MARKERS: &[&str] = &["#%#", "#@%#", "#$#", "#@$#", "$$", "$@$"];
+
+fn is_comment(line: &str) -> bool {
+    line.is_empty() || line.starts_with('!') || line.starts_with('[')
+}
+
+fn is_hostname(text: &str) -> bool {
+    text.contains('.')
+        && text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_')
+        && !text.starts_with('.')
+        && !text.ends_with('.')
+}
+
+fn hosts_entry(line: &str) -> Option<Vec<&str>> {
+    let line = line.split('#').next()?.trim();
+    let mut fields = line.split_whitespace();
+    let address = fields.next()?;
+    if !BLACKHOLE_ADDRESSES.contains(&address) {
+        return None;
+    }
+    Some(fields.collect())
+}
+
+pub fn detect(text: &str) -> ListFormat {
+    let header = text
+        .lines()
+        .take(20)
+        .map(|line| line.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+
+    if header.iter().any(|line| line.starts_with("[adguard")) {
+        return ListFormat::AdGuard;
+    }
+    if header
+        .iter()
+        .any(|line| line.starts_with("[adblock") || line.starts_with("[ublock"))
+    {
+        return ListFormat::Adblock;
+    }
+
+    let sample = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with('!'))
+        .take(SAMPLE_LINES)
+        .collect::<Vec<_>>();
+    if sample.is_empty() {
+        return ListFormat::Adblock;
+    }
+
+    let hosts = sample
+        .iter()
+        .filter(|line| hosts_entry(line).is_some())
+        .count();
+    let domains = sample.iter().filter(|line| is_hostname(line)).count();
+    let adguard = sample
+        .iter()
+        .filter(|line| ADGUARD_ONLY_MARKERS.iter().any(|m| line.contains(m)))
+        .count();
+
+    if hosts * 2 > sample.len() {
+        ListFormat::Hosts
+    } else if domains * 2 > sample.len() {
+        ListFormat::Domains
+    } else if adguard > 0 {
+        ListFormat::AdGuard
+    } else {
+        ListFormat::Adblock
+    }
+}
+
+/// Rules of a list, converted from its format.
+#[derive(Debug, Default)]
+pub struct Converted {
+    pub rules: Vec<String>,
+    /// Lines dropped because the syntax has no adblock equivalent.
+    pub unsupported: usize,
+}
+
+fn domain_rule(domain: &str) -> Option<String> {
+    let domain = domain.trim().trim_end_matches('.').to_ascii_lowercase();
+    if !is_hostname(&domain) || LOCAL_HOSTNAMES.contains(&domain.as_str()) {
+        return None;
+    }
+    Some(format!("||{}^", domain))
+}
+
+pub fn convert(text: &str, format: ListFormat) -> Converted {
+    let mut converted = Converted::default();
+
+    for line in text.lines().map(str::trim) {
+        match format {
+            ListFormat::Adblock | ListFormat::AdGuard => {
+                if is_comment(line) {
+                    continue;
+                }
+                if format == ListFormat::AdGuard
+                    && ADGUARD_ONLY_MARKERS.iter().any(|m| line.contains(m))
+                {
+                    converted.unsupported += 1;
+                    continue;
+                }
+                converted.rules.push(line.to_string());
+            }
+            ListFormat::Hosts => {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                match hosts_entry(line) {
+                    // A `0.0.0.0 0.0.0.0` or `127.0.0.1 localhost` line adds
+                    // nothing, but isn't an error either.
+                    Some(names) => converted
+                        .rules
+                        .extend(names.into_iter().filter_map(domain_rule)),
+                    None => converted.unsupported += 1,
+                }
+            }
+            ListFormat::Domains => {
+                let line = line.split('#').next().unwrap_or("").trim();
+                if line.is_empty() {
+                    continue;
+                }
+                match domain_rule(line) {
+                    Some(rule) => converted.rules.push(rule),
+                    None => converted.unsupported += 1,
+                }
+            }
+        }
+    }
+
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_formats() {
+        let cases = [
+            (
+                "# Hosts file\n127.0.0.1 localhost\n::1 localhost\n0.0.0.0 ads.example.com\n0.0.0.0 tracker.example.net",
+                ListFormat::Hosts,
+            ),
+            (
+                "# Blocklist\nads.example.com\ntracker.example.net # comment\nexample.org",
+                ListFormat::Domains,
+            ),
+            ("[AdGuard]\n||example.com^", ListFormat::AdGuard),
+            ("[Adblock Plus 2.0]\n||example.com^", ListFormat::Adblock),
+            ("! Title: uBO\n[uBlock Origin]\n##.ad", ListFormat::Adblock),
+            (
+                "! Title: Mixed\n||example.com^\nexample.org#%#window.ads = 1;",
+                ListFormat::AdGuard,
+            ),
+            (
+                "! Title: Plain\n||example.com^\n##.banner\n@@||example.org^",
+                ListFormat::Adblock,
+            ),
+            ("", ListFormat::Adblock),
+        ];
+
+        for (text, format) in cases {
+            assert_eq!(detect(text), format, "list {:?}", text);
+        }
+    }
+
+    #[test]
+    fn converts_hosts_lines() {
+        let cases = [
+            ("0.0.0.0 example.com # comment", vec!["||example.com^"], 0),
+            ("0.0.0.0 Example.COM.", vec!["||example.com^"], 0),
+            (
+                "127.0.0.1 ads.example.com tracker.example.com",
+                vec!["||ads.example.com^", "||tracker.example.com^"],
+                0,
+            ),
+            (":: ads.example.com", vec!["||ads.example.com^"], 0),
+            ("::1 ads.example.com", vec!["||ads.example.com^"], 0),
+            ("127.0.0.1 localhost", vec![], 0),
+            ("127.0.0.1 localhost.localdomain", vec![], 0),
+            ("::1 localhost ip6-localhost ip6-loopback", vec![], 0),
+            ("0.0.0.0 0.0.0.0", vec![], 0),
+            ("# 0.0.0.0 example.com", vec![], 0),
+            ("", vec![], 0),
+            ("255.255.255.255 broadcasthost", vec![], 1),
+            ("fe80::1%lo0 localhost", vec![], 1),
+            ("||example.com^", vec![], 1),
+        ];
+
+        for (line, rules, unsupported) in cases {
+            let converted = convert(line, ListFormat::Hosts);
+            assert_eq!(converted.rules, rules, "line {:?}", line);
+            assert_eq!(converted.unsupported, unsupported, "line {:?}", line);
+        }
+    }
+
+    #[test]
+    fn converts_domain_lines() {
+        let cases = [
+            ("example.com", vec!["||example.com^"], 0),
+            ("Ads.Example.com.", vec!["||ads.example.com^"], 0),
+            (
+                "tracker.example.net # comment",
+                vec!["||tracker.example.net^"],
+                0,
+            ),
+            ("# comment", vec![], 0),
+            ("", vec![], 0),
+            ("localhost.localdomain", vec![], 1),
+            ("localhost", vec![], 1),
+            ("*.example.com", vec![], 1),
+            ("https://example.com/", vec![], 1),
+        ];
+
+        for (line, rules, unsupported) in cases {
+            let converted = convert(line, ListFormat::Domains);
+            assert_eq!(converted.rules, rules, "line {:?}", line);
+            assert_eq!(converted.unsupported, unsupported, "line {:?}", line);
+        }
+    }
+
+    #[test]
+    fn drops_adguard_only_syntax() {
+        let cases = [
+            (
+                "||example.com^$third-party",
+                vec!["||example.com^$third-party"],
+                0,
+            ),
+            ("example.com##.banner", vec!["example.com##.banner"], 0),
+            ("example.com#%#window.ads = 1;", vec![], 1),
+            ("example.com#@%#window.ads = 1;", vec![], 1),
+            ("example.com#$#.ad { display: none; }", vec![], 1),
+            ("example.com#@$#.ad { display: none; }", vec![], 1),
+            ("example.com$$script[data-ad]", vec![], 1),
+            ("example.com$@$script[data-ad]", vec![], 1),
+            ("! comment", vec![], 0),
+            ("[AdGuard]", vec![], 0),
+        ];
+
+        for (line, rules, unsupported) in cases {
+            let converted = convert(line, ListFormat::AdGuard);
+            assert_eq!(converted.rules, rules, "line {:?}", line);
+            assert_eq!(converted.unsupported, unsupported, "line {:?}", line);
+        }
+    }
+
+    #[test]
+    fn keeps_adguard_syntax_in_adblock_lists() {
+        // Only lists detected as AdGuard have these lines dropped; adblock
+        // lists pass everything through and let the parser decide.
+        let converted = convert("example.com#%#window.ads = 1;", ListFormat::Adblock);
+        assert_eq!(converted.rules, ["example.com#%#window.ads = 1;"]);
+        assert_eq!(converted.unsupported, 0);
+    }
+
+    #[test]
+    fn counts_a_whole_list() {
+        let text = "\
+# Title: Example hosts
+127.0.0.1 localhost
+::1 localhost ip6-localhost
+255.255.255.255 broadcasthost
+
+0.0.0.0 ads.example.com # ads
+0.0.0.0 tracker.example.com
+0.0.0.0 ads.example.com
+fe80::1%lo0 localhost
+";
+        let format = detect(text);
+        assert_eq!(format, ListFormat::Hosts);
+
+        let converted = convert(text, format);
+        assert_eq!(
+            converted.rules,
+            [
+                "||ads.example.com^",
+                "||tracker.example.com^",
+                "||ads.example.com^",
+            ]
+        );
+        assert_eq!(converted.unsupported, 2);
+    }
+}
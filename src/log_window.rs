@@ -0,0 +1,195 @@
+//! Window listing the requests checked in one tab, updated as they come in.
+
+use crate::content_filter::ContentFilter;
+use crate::request_log::{self, LogEntry, RequestLog};
+use gtk::prelude::*;
+use gtk::{
+    CellRendererText, ComboBoxText, ListStore, Orientation, ScrolledWindow, TreeModelFilter,
+    TreeView, TreeViewColumn, Window, WindowType,
+};
+use std::cell::Cell;
+use std::rc::Rc;
+use webkit2gtk::WebViewExt;
+
+const COLUMN_RESULT: u32 = 0;
+const COLUMN_TYPE: u32 = 1;
+const COLUMN_URL: u32 = 2;
+const COLUMN_RULE: u32 = 3;
+const COLUMN_BLOCKED: u32 = 4;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Show {
+    All,
+    Blocked,
+    Allowed,
+}
+
+fn result_text(entry: &LogEntry) -> &'static str {
+    if entry.redirected {
+        "redirected"
+    } else if entry.blocked {
+        "blocked"
+    } else if entry.exception.is_some() {
+        "excepted"
+    } else {
+        "allowed"
+    }
+}
+
+fn append_entry(store: &ListStore, entry: &LogEntry) {
+    let rule = match (&entry.filter, &entry.exception) {
+        (Some(filter), Some(exception)) => format!("{}  (except {})", filter, exception),
+        (Some(filter), None) => filter.clone(),
+        (None, Some(exception)) => exception.clone(),
+        (None, None) => String::new(),
+    };
+
+    store.insert_with_values(
+        None,
+        &[
+            (COLUMN_RESULT, &result_text(entry)),
+            (COLUMN_TYPE, &entry.request_type),
+            (COLUMN_URL, &entry.url),
+            (COLUMN_RULE, &rule),
+            (COLUMN_BLOCKED, &entry.blocked),
+        ],
+    );
+}
+
+fn add_column(tree_view: &TreeView, title: &str, column: u32) {
+    let renderer = CellRendererText::new();
+    let tree_column = TreeViewColumn::new();
+    tree_column.set_title(title);
+    tree_column.set_resizable(true);
+    tree_column.pack_start(&renderer, true);
+    tree_column.add_attribute(&renderer, "text", column as i32);
+    tree_view.append_column(&tree_column);
+}
+
+/// Shows the log of `webview`. While it is open the tab goes without
+/// WebKit's content blocker, whose blocks the log can't see.
+pub fn show_log_window(
+    log: &RequestLog,
+    content_filter: &ContentFilter,
+    webview: &webkit2gtk::WebView,
+) {
+    let window = Window::new(WindowType::Toplevel);
+    let title = webview
+        .uri()
+        .map(|uri| format!("Request log - {}", uri))
+        .unwrap_or_else(|| "Request log".to_string());
+    window.set_title(&title);
+    window.set_default_size(900, 500);
+
+    let store = ListStore::new(&[
+        String::static_type(),
+        String::static_type(),
+        String::static_type(),
+        String::static_type(),
+        bool::static_type(),
+    ]);
+    for entry in log.entries(webview) {
+        append_entry(&store, &entry);
+    }
+
+    let show = Rc::new(Cell::new(Show::All));
+    let filter = TreeModelFilter::new(&store, None);
+    filter.set_visible_func({
+        let show = show.clone();
+
+        move |model, iter| {
+            let blocked = model
+                .value(iter, COLUMN_BLOCKED as i32)
+                .get::<bool>()
+                .unwrap_or(false);
+
+            match show.get() {
+                Show::All => true,
+                Show::Blocked => blocked,
+                Show::Allowed => !blocked,
+            }
+        }
+    });
+
+    let tree_view = TreeView::with_model(&filter);
+    add_column(&tree_view, "Result", COLUMN_RESULT);
+    add_column(&tree_view, "Type", COLUMN_TYPE);
+    add_column(&tree_view, "URL", COLUMN_URL);
+    add_column(&tree_view, "Rule", COLUMN_RULE);
+
+    let show_combo = ComboBoxText::new();
+    show_combo.append_text("All requests");
+    show_combo.append_text("Blocked");
+    show_combo.append_text("Allowed");
+    show_combo.set_active(Some(0));
+    show_combo.connect_changed({
+        let filter = filter.clone();
+
+        move |combo| {
+            show.set(match combo.active() {
+                Some(1) => Show::Blocked,
+                Some(2) => Show::Allowed,
+                _ => Show::All,
+            });
+            filter.refilter();
+        }
+    });
+
+    let blocked_label = gtk::Label::new(Some(&request_log::blocked_text(log.blocked(webview))));
+    blocked_label.set_xalign(0.0);
+
+    let listener = log.subscribe({
+        let log = log.clone();
+        let store = store.clone();
+        let blocked_label = blocked_label.clone();
+        let logged = webview.clone();
+
+        move |webview, entry| {
+            if *webview != logged {
+                return;
+            }
+
+            match entry {
+                Some(entry) => append_entry(&store, entry),
+                None => store.clear(),
+            }
+            blocked_label.set_text(&request_log::blocked_text(log.blocked(webview)));
+        }
+    });
+
+    log.log_allowed(webview);
+    content_filter.pause(webview);
+
+    window.connect_destroy({
+        let log = log.clone();
+        let content_filter = content_filter.clone();
+        let webview = webview.clone();
+
+        move |_| {
+            log.unsubscribe(listener);
+            log.stop_logging_allowed(&webview);
+            content_filter.resume(&webview);
+        }
+    });
+
+    let note = gtk::Label::new(Some(&format!(
+        "{} The content blocker is off for this tab while the log is open, and \
+         allowed requests are only logged from when it opened; reload the page \
+         to log all of its requests.",
+        request_log::CONTENT_BLOCKER_NOTE
+    )));
+    note.set_line_wrap(true);
+    note.set_xalign(0.0);
+
+    let scrolled_window = ScrolledWindow::new(gtk::Adjustment::NONE, gtk::Adjustment::NONE);
+    scrolled_window.add(&tree_view);
+
+    let vbox = gtk::Box::new(Orientation::Vertical, 5);
+    vbox.pack_start(&note, false, false, 5);
+    vbox.pack_start(&blocked_label, false, false, 0);
+    vbox.pack_start(&show_combo, false, false, 5);
+    vbox.pack_start(&scrolled_window, true, true, 0);
+
+    window.add(&vbox);
+    window.show_all();
+}
@@ -2,24 +2,37 @@ extern crate gtk;
 extern crate webkit2gtk;
 
 mod adblock_abrw;
+mod allowlist;
+mod bangs;
+mod bookmarks;
+mod calc;
+mod closed_tabs;
 mod connections;
+mod content_filter;
+mod cosmetic;
+mod domain_list;
+mod history;
+mod https_only;
+mod list_format;
+mod log_window;
+mod omnibox;
+mod opensearch;
+mod query;
+mod request_log;
 mod search;
 mod settings;
+mod site_settings;
+mod suggestions;
 mod tabs;
+mod url_clean;
 mod utils;
 
-use adblock::lists::{FilterSet, ParseOptions};
 use gtk::gdk_pixbuf::Pixbuf;
 use gtk::glib::{ControlFlow, MainContext};
 use gtk::{glib::Propagation, prelude::*};
-use rayon::prelude::*;
-use search::fetch_suggestions;
-use serde_json::Value;
+use omnibox::Input;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use std::thread;
 use url::Url;
-use utils::is_url;
 use webkit2gtk::CookieManagerExt;
 use webkit2gtk::WebContext;
 use webkit2gtk::WebContextExt;
@@ -29,6 +42,7 @@ fn main() {
     gtk::init().unwrap();
 
     let web_context = WebContext::default().unwrap();
+    adblock_abrw::setup_web_extension(&web_context);
 
     let storage_file_path = "cookies.sqlite";
     let cookiea = WebContextExt::cookie_manager(&web_context).unwrap();
@@ -59,48 +73,14 @@ fn main() {
         Propagation::Stop
     });
 
-    let initial_rules = vec![
-        String::from("-advertisement-icon."),
-        String::from("-advertisement-management/"),
-        String::from("-advertisement."),
-        String::from("-advertisement/script."),
-    ];
-
-    let urls = vec![
-        "https://easylist.to/easylist/easylist.txt",
-        "https://ublockorigin.github.io/uAssets/thirdparties/easylist-cookies.txt",
-        "https://ublockorigin.github.io/uAssets/filters/annoyances-cookies.txt",
-        "https://ublockorigin.github.io/uAssets/thirdparties/easylist-newsletters.txt",
-        "https://ublockorigin.github.io/uAssets/filters/annoyances-others.txt",
-        "https://ublockorigin.github.io/uAssets/thirdparties/easylist-social.txt",
-        "https://ublockorigin.github.io/uAssets/thirdparties/easylist-chat.txt",
-        "https://ublockorigin.github.io/uAssets/thirdparties/easylist-annoyances.txt",
-        "https://raw.githubusercontent.com/StevenBlack/hosts/master/hosts",
-    ];
-
-    let rules = Arc::new(Mutex::new(initial_rules));
-
-    let filter_set = Arc::new(Mutex::new(FilterSet::new(true)));
-
-    let rules_clone = Arc::clone(&rules);
-    let filter_set_clone = Arc::clone(&filter_set);
-
-    thread::spawn(move || {
-        urls.par_iter().for_each(|url| {
-            if let Ok(fetched_rules) = adblock_abrw::fetch_block_list(url) {
-                // Lock the Mutex to safely access and modify `rules`
-                let mut rules_guard = rules_clone.lock().unwrap();
-                rules_guard.extend(fetched_rules);
-
-                let mut filter_set_guard = filter_set_clone.lock().unwrap();
-                filter_set_guard.add_filters(&*rules_guard, ParseOptions::default());
-            }
-        });
+    // Start from the engine built on the previous run so the first tabs are
+    // already covered, then refresh the lists in the background.
+    let engine = adblock_abrw::SharedEngine::new(adblock_abrw::load_cached_engine());
 
-        MainContext::default().invoke(move || println!("Fetched ad block rules in the background"))
-    });
+    let (engine_sender, engine_receiver) = MainContext::channel::<()>(gtk::glib::Priority::DEFAULT);
 
-    // Process each URL in parallel
+    let list_updater = adblock_abrw::ListUpdater::spawn(engine.clone(), engine_sender);
+    list_updater.update();
 
     let vbox = gtk::Box::new(gtk::Orientation::Vertical, 0);
     let hbox = gtk::Box::new(gtk::Orientation::Horizontal, 0);
@@ -143,16 +123,20 @@ fn main() {
     let forward_button = gtk::Button::with_label(">");
     let refresh_button = gtk::Button::with_label("↻");
     let new_tab_button = gtk::Button::with_label("+");
+    let closed_tabs_button = gtk::Button::with_label("↶");
+    closed_tabs_button.set_tooltip_text(Some("Recently closed tabs"));
 
     back_button.set_size_request(30, 30);
     forward_button.set_size_request(30, 30);
     refresh_button.set_size_request(30, 30);
     new_tab_button.set_size_request(30, 30);
+    closed_tabs_button.set_size_request(30, 30);
 
     hbox.pack_start(&back_button, false, false, 5);
     hbox.pack_start(&forward_button, false, false, 5);
     hbox.pack_start(&refresh_button, false, false, 5);
     hbox.pack_start(&new_tab_button, false, false, 5);
+    hbox.pack_start(&closed_tabs_button, false, false, 5);
 
     let css_provider = gtk::CssProvider::new();
     css_provider
@@ -178,66 +162,17 @@ fn main() {
     let forward_button_style = forward_button.style_context();
     let refresh_button_style = refresh_button.style_context();
     let new_tab_button_style = new_tab_button.style_context();
+    let closed_tabs_button_style = closed_tabs_button.style_context();
 
     back_button_style.add_provider(&css_provider, gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
     forward_button_style.add_provider(&css_provider, gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
     refresh_button_style.add_provider(&css_provider, gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
     new_tab_button_style.add_provider(&css_provider, gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
+    closed_tabs_button_style.add_provider(&css_provider, gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
 
     let search_box = gtk::Box::new(gtk::Orientation::Horizontal, 0);
     let search_entry = gtk::Entry::new();
 
-    let completion = gtk::EntryCompletion::new();
-    search_entry.set_completion(Some(&completion));
-
-    let store = gtk::ListStore::new(&[gtk::glib::Type::STRING]);
-
-    completion.set_model(Some(&store));
-    completion.set_text_column(0);
-
-    let (sender, receiver) = gtk::glib::MainContext::channel::<Result<Value, std::io::Error>>(
-        gtk::glib::Priority::DEFAULT,
-    );
-
-    search_entry.connect_changed(move |e| {
-        let text = e.text();
-        let sender = sender.clone();
-
-        if !text.is_empty() && !is_url(&text) {
-            let text_clone = text.clone();
-
-            thread::spawn(move || {
-                let result = fetch_suggestions(&text_clone);
-                println!("Fetched suggestions");
-                sender.send(result).expect("Failed to send");
-            });
-        };
-    });
-
-    receiver.attach(None, move |result| {
-        store.clear();
-
-        match result {
-            Ok(suggestions) => {
-                if let Some(suggestions_array) = suggestions.as_array() {
-                    for suggestion in suggestions_array {
-                        if let Some(phrase) = suggestion.get("phrase").and_then(Value::as_str) {
-                            println!("{}", &phrase);
-                            store.insert_with_values(None, &[(0, &phrase.to_value())]);
-                            // THIS DOESNT WORK
-                        }
-                    }
-
-                    completion.set_model(Some(&store));
-                    completion.set_text_column(0);
-                }
-            }
-
-            Err(err) => println!("Error: {}", err),
-        }
-        ControlFlow::Continue
-    });
-
     let css_provider = gtk::CssProvider::new();
     css_provider
         .load_from_data(
@@ -260,12 +195,6 @@ fn main() {
     search_entry.set_icon_from_pixbuf(gtk::EntryIconPosition::Secondary, Some(&icon));
     search_entry.set_icon_tooltip_text(gtk::EntryIconPosition::Secondary, Some("Site settings"));
 
-    search_entry.connect_icon_press(move |_, icon_pos, _| {
-        if icon_pos == gtk::EntryIconPosition::Secondary {
-            println!("Clicked popup")
-        }
-    });
-
     let css_provider = gtk::CssProvider::new();
     css_provider
         .load_from_data(
@@ -353,16 +282,82 @@ fn main() {
 
     vbox.pack_start(&notebook, true, true, 0);
 
-    tabs::add_webview_tab(
-        &notebook,
-        "https://start.duckduckgo.com/",
-        "New tab",
-        &search_entry,
-        &filter_set.clone(),
-    );
+    let content_filter = content_filter::ContentFilter::default();
+    content_filter.load(&notebook);
+
+    let browser = tabs::Browser {
+        notebook: notebook.clone(),
+        search_entry: search_entry.clone(),
+        engine,
+        content_filter,
+        list_updater,
+        request_log: request_log::RequestLog::default(),
+        opensearch: Default::default(),
+        openers: Default::default(),
+        closed_tabs: Default::default(),
+        history: history::SharedHistory::load(),
+    };
+
+    suggestions::setup(&browser);
+
+    engine_receiver.attach(None, {
+        let browser = browser.clone();
+
+        move |_| {
+            adblock_abrw::notify_engine_updated(&WebContext::default().unwrap());
+
+            browser.content_filter.compile(&browser.notebook);
+            println!("Fetched ad block rules in the background");
+            ControlFlow::Continue
+        }
+    });
+
+    search_entry.connect_icon_press({
+        let browser = browser.clone();
+
+        move |search_entry, icon_pos, _| {
+            if icon_pos == gtk::EntryIconPosition::Secondary {
+                site_settings::show_site_settings(&browser, search_entry);
+            }
+        }
+    });
+
+    // Addresses and searches given on the command line open in tabs of
+    // their own, the first one in front.
+    let urls = std::env::args()
+        .skip(1)
+        .map(|arg| match omnibox::classify(&arg) {
+            Input::Url(url) => url,
+            Input::Search(text) => search::search_url(&text),
+        })
+        .collect::<Vec<_>>();
+
+    if urls.is_empty() {
+        let start_url = settings::Settings::load()
+            .default_search_engine()
+            .home_url();
+        tabs::add_webview_tab(
+            &browser,
+            tabs::TabOptions {
+                url: Some(start_url),
+                ..Default::default()
+            },
+        );
+    }
+    for (i, url) in urls.into_iter().enumerate() {
+        tabs::add_webview_tab(
+            &browser,
+            tabs::TabOptions {
+                url: Some(url),
+                background: i > 0,
+                ..Default::default()
+            },
+        );
+    }
 
     search_entry.connect_activate({
         let notebook = notebook.clone();
+        let engine = browser.engine.clone();
 
         move |search_entry| {
             let current_page = notebook.current_page();
@@ -375,28 +370,26 @@ fn main() {
 
                     let url_str = url.to_string();
 
-                    let url = match Url::parse(&url_str) {
-                        Ok(parsed_url) => parsed_url,
-                        Err(_) => {
-                            let search_query = url.replace(" ", "+");
-                            webview
-                                .load_uri(&format!("https://duckduckgo.com/?q={}", search_query));
-                            return;
+                    if let Some(bang_url) = bangs::resolve(&url_str) {
+                        webview.load_uri(&bang_url);
+                        return;
+                    }
+
+                    // The answer is in the first suggestion row; Enter copies
+                    // it instead of sending the calculation to the engine.
+                    if let Some(answer) = calc::evaluate(&url_str) {
+                        calc::copy(&answer.value);
+                        return;
+                    }
+
+                    match omnibox::classify(&url_str) {
+                        Input::Url(url) => {
+                            let cleaned = Url::parse(&url)
+                                .ok()
+                                .and_then(|url| url_clean::clean_url(&engine, &url));
+                            webview.load_uri(cleaned.as_deref().unwrap_or(&url));
                         }
-                    };
-
-                    if (url.scheme() == "http" && url.to_string().contains("."))
-                        || (url.scheme() == "https" && url.to_string().contains("."))
-                        || url.scheme() == "file"
-                    {
-                        webview.load_uri(&url.to_string());
-                    } else if url.scheme() == "localhost"
-                        || url.host_str().unwrap_or("").contains("localhost")
-                    {
-                        webview.load_uri(&url.to_string());
-                    } else {
-                        let search_query = url.to_string().replace(" ", "+");
-                        webview.load_uri(&format!("https://duckduckgo.com/?q={}", search_query));
+                        Input::Search(text) => webview.load_uri(&search::search_url(&text)),
                     }
                 }
             }
@@ -425,19 +418,30 @@ fn main() {
         window_clone.close(); // Close the window
     });
 
-    options.connect_clicked(move |_| {
-        settings::show_settings_window();
+    options.connect_clicked({
+        let list_updater = browser.list_updater.clone();
+
+        move |_| {
+            settings::show_settings_window(&list_updater);
+        }
     });
 
     connections::back_button_clicked(&notebook, &back_button);
     connections::forward_button_clicked(&notebook, &forward_button);
     connections::refresh_button_clicked(&notebook, &refresh_button);
     connections::notebook_switch_page(&notebook, &search_entry);
-    connections::new_tab_button_clicked(&new_tab_button, &notebook, &search_entry, &filter_set);
+    connections::new_tab_button_clicked(&new_tab_button, &browser);
+    connections::closed_tabs_button_clicked(&closed_tabs_button, &browser);
+    connections::window_key_press(&window, &browser);
 
-    window.connect_delete_event(|_, _| {
-        gtk::main_quit();
-        Propagation::Stop
+    window.connect_delete_event({
+        let history = browser.history.clone();
+
+        move |_, _| {
+            history.flush();
+            gtk::main_quit();
+            Propagation::Stop
+        }
     });
 
     search_entry.set_is_focus(true);
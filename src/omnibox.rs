@@ -0,0 +1,270 @@
+//! Telling addresses from searches in what is typed into the URL bar.
+//!
+//! Anything with a scheme the browser loads is an address. Otherwise the
+//! text is taken apart as `host[:port][/path]`: IP literals, `localhost`,
+//! single-label intranet hosts with a port or path, and names under a known
+//! public suffix are addresses, everything else is searched for. The public
+//! suffix list is compiled into the `psl` crate, so this works offline.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use url::{Host, Url};
+
+/// Schemes loaded when typed with them; `javascript:` and the like are not.
+const KNOWN_SCHEMES: &[&str] = &["http", "https", "file", "about", "data", "view-source"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    /// An address to load, normalized.
+    Url(String),
+    /// Text for the search engine.
+    Search(String),
+}
+
+impl Input {
+    pub fn is_url(&self) -> bool {
+        matches!(self, Input::Url(_))
+    }
+}
+
+pub fn classify(text: &str) -> Input {
+    classify_in(text, &gtk::glib::home_dir())
+}
+
+/// `classify` with `~` standing for `home`.
+fn classify_in(text: &str, home: &Path) -> Input {
+    let text = text.trim();
+    let search = || Input::Search(text.to_string());
+    if text.is_empty() {
+        return search();
+    }
+
+    if let Some(url) = file_path_url(text, home) {
+        return Input::Url(url);
+    }
+
+    if let Ok(url) = Url::parse(text) {
+        if KNOWN_SCHEMES.contains(&url.scheme()) {
+            return Input::Url(url.to_string());
+        }
+    }
+
+    // Sentences, and e-mail addresses, are searches.
+    if text.contains(char::is_whitespace) || text.contains('@') {
+        return search();
+    }
+
+    let end = text.find(['/', '?', '#']).unwrap_or(text.len());
+    let (authority, rest) = text.split_at(end);
+    let (host, port) = match split_port(authority) {
+        Some(parts) => parts,
+        None => return search(),
+    };
+    let scheme = match host_scheme(host, port.is_some(), !rest.is_empty()) {
+        Some(scheme) => scheme,
+        None => return search(),
+    };
+
+    let host = if host.parse::<Ipv6Addr>().is_ok() {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    };
+    let port = port.map(|port| format!(":{}", port)).unwrap_or_default();
+
+    match Url::parse(&format!("{}://{}{}{}", scheme, host, port, rest)) {
+        Ok(url) => Input::Url(url.to_string()),
+        Err(_) => search(),
+    }
+}
+
+/// `file://` URL for absolute and `~/` paths.
+fn file_path_url(text: &str, home: &Path) -> Option<String> {
+    let path = if let Some(relative) = text.strip_prefix("~/") {
+        home.join(relative)
+    } else if text.starts_with('/') {
+        Path::new(text).to_path_buf()
+    } else {
+        return None;
+    };
+
+    Url::from_file_path(path).ok().map(|url| url.to_string())
+}
+
+/// Splits `host:port`, keeping brackets around IPv6 literals. `None` if the
+/// port isn't one.
+fn split_port(authority: &str) -> Option<(&str, Option<&str>)> {
+    let (host, port) = if authority.starts_with('[') {
+        let end = authority.find(']')?;
+        let (host, after) = authority.split_at(end + 1);
+        if after.is_empty() {
+            (host, None)
+        } else {
+            (host, Some(after.strip_prefix(':')?))
+        }
+    } else if authority.parse::<Ipv6Addr>().is_ok() {
+        (authority, None)
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+
+    // `parse` alone would take `+80`.
+    let valid =
+        |port: &str| port.chars().all(|c| c.is_ascii_digit()) && port.parse::<u16>().is_ok();
+    match port {
+        Some(port) if !valid(port) => None,
+        _ => Some((host, port)),
+    }
+}
+
+fn is_label(label: &str) -> bool {
+    !label.is_empty()
+        && label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The scheme to load `host` with, or `None` if it should be searched for.
+fn host_scheme(host: &str, has_port: bool, has_path: bool) -> Option<&'static str> {
+    if let Some(literal) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        return literal.parse::<Ipv6Addr>().ok().map(|_| "http");
+    }
+    if host.parse::<Ipv6Addr>().is_ok() || host.parse::<Ipv4Addr>().is_ok() {
+        return Some("http");
+    }
+
+    // IDNs are looked up by their ASCII form. Numbers the URL parser takes
+    // for IPv4 addresses, like `3.14`, weren't dotted quads above.
+    let ascii = match Host::parse(host) {
+        Ok(Host::Domain(domain)) => domain,
+        _ => return None,
+    };
+    let ascii = ascii.strip_suffix('.').unwrap_or(&ascii);
+    if !ascii.split('.').all(is_label) {
+        return None;
+    }
+
+    if ascii == "localhost" || ascii.ends_with(".localhost") {
+        return Some("http");
+    }
+
+    // `router:8080` and `intranet/` are hosts, `rust` is a search.
+    if !ascii.contains('.') {
+        return (has_port || has_path).then_some("http");
+    }
+
+    let known_suffix = psl::suffix(ascii.as_bytes()).is_some_and(|suffix| suffix.is_known());
+    if known_suffix && psl::domain(ascii.as_bytes()).is_some() {
+        return Some("https");
+    }
+
+    // Names on a local network, like `nas.lan:5000`.
+    has_port.then_some("http")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(url: &str) -> Input {
+        Input::Url(url.to_string())
+    }
+
+    fn search(text: &str) -> Input {
+        Input::Search(text.to_string())
+    }
+
+    #[test]
+    fn classifies_input() {
+        let cases = [
+            // Scheme given.
+            ("http://example.com", url("http://example.com/")),
+            ("https://example.com/a b", url("https://example.com/a%20b")),
+            ("HTTPS://EXAMPLE.COM/Path", url("https://example.com/Path")),
+            ("http://localhost:8080", url("http://localhost:8080/")),
+            ("file:///etc/hosts", url("file:///etc/hosts")),
+            ("about:blank", url("about:blank")),
+            (
+                "view-source:https://example.com",
+                url("view-source:https://example.com"),
+            ),
+            ("data:text/plain,hi", url("data:text/plain,hi")),
+            ("javascript:alert(1)", search("javascript:alert(1)")),
+            ("mailto:me@example.com", search("mailto:me@example.com")),
+            // Bare domains.
+            ("example.com", url("https://example.com/")),
+            ("  example.com  ", url("https://example.com/")),
+            ("Example.COM", url("https://example.com/")),
+            ("example.com.", url("https://example.com./")),
+            (
+                "www.example.co.uk/path?q=1#top",
+                url("https://www.example.co.uk/path?q=1#top"),
+            ),
+            ("example.com:8443", url("https://example.com:8443/")),
+            ("example.com?q=a", url("https://example.com/?q=a")),
+            (
+                "sub.domain.example.org/x",
+                url("https://sub.domain.example.org/x"),
+            ),
+            // IDNs.
+            ("münchen.de", url("https://xn--mnchen-3ya.de/")),
+            ("пример.рф", url("https://xn--e1afmkfd.xn--p1ai/")),
+            ("xn--mnchen-3ya.de", url("https://xn--mnchen-3ya.de/")),
+            // IP literals.
+            ("127.0.0.1", url("http://127.0.0.1/")),
+            (
+                "192.168.1.1:8080/admin",
+                url("http://192.168.1.1:8080/admin"),
+            ),
+            ("[::1]", url("http://[::1]/")),
+            ("[::1]:8080", url("http://[::1]:8080/")),
+            ("::1", url("http://[::1]/")),
+            ("[2001:db8::1]/x", url("http://[2001:db8::1]/x")),
+            ("[not:an:ip]", search("[not:an:ip]")),
+            // Local hosts.
+            ("localhost", url("http://localhost/")),
+            ("localhost:8080", url("http://localhost:8080/")),
+            ("LOCALHOST:3000/api", url("http://localhost:3000/api")),
+            ("app.localhost", url("http://app.localhost/")),
+            ("router:8080", url("http://router:8080/")),
+            ("intranet/", url("http://intranet/")),
+            ("nas/share", url("http://nas/share")),
+            ("nas.lan:5000", url("http://nas.lan:5000/")),
+            // File paths.
+            ("/etc/hosts", url("file:///etc/hosts")),
+            ("/tmp/My File.txt", url("file:///tmp/My%20File.txt")),
+            ("~/notes.txt", url("file:///home/user/notes.txt")),
+            ("~/a b/c", url("file:///home/user/a%20b/c")),
+            // Searches.
+            ("", search("")),
+            ("rust", search("rust")),
+            ("rust lang", search("rust lang")),
+            ("w rust", search("w rust")),
+            ("what is example.com", search("what is example.com")),
+            ("example.com is down", search("example.com is down")),
+            ("rust.lang", search("rust.lang")),
+            ("nas.lan", search("nas.lan")),
+            ("co.uk", search("co.uk")),
+            ("3.14", search("3.14")),
+            ("1.2.3", search("1.2.3")),
+            ("999.1.1.1", search("999.1.1.1")),
+            ("c++", search("c++")),
+            ("example..com", search("example..com")),
+            ("me@example.com", search("me@example.com")),
+            ("example.com:99999", search("example.com:99999")),
+            ("example.com:http", search("example.com:http")),
+            ("localhost:", search("localhost:")),
+            ("http://", search("http://")),
+            ("~", search("~")),
+            ("?", search("?")),
+        ];
+
+        let home = Path::new("/home/user");
+        for (text, expected) in cases {
+            assert_eq!(classify_in(text, home), expected, "input {:?}", text);
+        }
+    }
+}
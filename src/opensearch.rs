@@ -0,0 +1,471 @@
+//! Adding search engines that sites advertise with OpenSearch.
+//!
+//! A user script reports the page's
+//! `<link rel="search" type="application/opensearchdescription+xml">`
+//! elements once it has loaded, and the site settings popover offers to add
+//! them. The description is fetched off the main loop and parsed for the
+//! results and suggestion URL templates and the icon.
+
+use crate::search::SearchEngine;
+use crate::settings::Settings;
+use gtk::glib::{self, ControlFlow, MainContext};
+use regex::Regex;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::rc::Rc;
+use std::sync::OnceLock;
+use std::thread;
+use webkit2gtk::{
+    LoadEvent, UserContentInjectedFrames, UserContentManagerExt, UserScript,
+    UserScriptInjectionTime, WebViewExt,
+};
+
+const MESSAGE_HANDLER: &str = "abrwOpenSearch";
+
+const DISCOVERY_SCRIPT: &str = r#"
+(function () {
+    const handler = window.webkit && window.webkit.messageHandlers.abrwOpenSearch;
+    if (!handler) {
+        return;
+    }
+
+    const selector = 'link[rel~="search"][type="application/opensearchdescription+xml"]';
+    const links = Array.from(document.querySelectorAll(selector))
+        .filter((link) => link.href)
+        .map((link) => ({ title: link.title || "", href: link.href }));
+    if (links.length) {
+        handler.postMessage(JSON.stringify(links));
+    }
+})();
+"#;
+
+/// An OpenSearch description a page links to.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SearchLink {
+    pub title: String,
+    pub href: String,
+}
+
+/// The OpenSearch links of the page shown in each tab.
+#[derive(Clone, Default)]
+pub struct Discovered {
+    tabs: Rc<RefCell<HashMap<webkit2gtk::WebView, Vec<SearchLink>>>>,
+}
+
+impl Discovered {
+    pub fn watch(&self, webview: &webkit2gtk::WebView) {
+        let manager = match webview.user_content_manager() {
+            Some(manager) => manager,
+            None => return,
+        };
+
+        manager.register_script_message_handler(MESSAGE_HANDLER);
+        manager.add_script(&UserScript::new(
+            DISCOVERY_SCRIPT,
+            UserContentInjectedFrames::TopFrame,
+            UserScriptInjectionTime::End,
+            &[],
+            &[],
+        ));
+
+        webview.connect_load_changed({
+            let tabs = self.tabs.clone();
+
+            move |webview, event| {
+                if event == LoadEvent::Started {
+                    tabs.borrow_mut().remove(webview);
+                }
+            }
+        });
+
+        let tabs = self.tabs.clone();
+        let webview = webview.downgrade();
+        manager.connect_script_message_received(Some(MESSAGE_HANDLER), move |_, result| {
+            let webview = match webview.upgrade() {
+                Some(webview) => webview,
+                None => return,
+            };
+            let message = match result.js_value() {
+                Some(value) => value.to_string(),
+                None => return,
+            };
+
+            match serde_json::from_str::<Vec<SearchLink>>(&message) {
+                Ok(links) => {
+                    tabs.borrow_mut().insert(webview, links);
+                }
+                Err(err) => eprintln!("Invalid OpenSearch message: {}", err),
+            }
+        });
+    }
+
+    pub fn forget(&self, webview: &webkit2gtk::WebView) {
+        self.tabs.borrow_mut().remove(webview);
+    }
+
+    pub fn links(&self, webview: &webkit2gtk::WebView) -> Vec<SearchLink> {
+        self.tabs.borrow().get(webview).cloned().unwrap_or_default()
+    }
+}
+
+/// `pattern`, compiled the first time it is needed.
+fn cached(cell: &'static OnceLock<Regex>, pattern: &str) -> &'static Regex {
+    cell.get_or_init(|| Regex::new(pattern).unwrap())
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn attributes(tag: &str) -> HashMap<String, String> {
+    static ATTRIBUTE: OnceLock<Regex> = OnceLock::new();
+    let attribute = cached(&ATTRIBUTE, r#"([\w:-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#);
+    attribute
+        .captures_iter(tag)
+        .map(|captures| {
+            let value = captures.get(2).or_else(|| captures.get(3)).unwrap();
+            (captures[1].to_lowercase(), unescape_xml(value.as_str()))
+        })
+        .collect()
+}
+
+/// Fills the parameters other than `{searchTerms}` that abrw has no use
+/// for. Optional ones (`{name?}`) are left out.
+fn fill_parameters(template: &str) -> String {
+    static PARAMETER: OnceLock<Regex> = OnceLock::new();
+    let parameter = cached(&PARAMETER, r"\{([^}]+)\}");
+    parameter
+        .replace_all(template, |captures: &regex::Captures| {
+            match &captures[1] {
+                "searchTerms" => "{searchTerms}",
+                "inputEncoding" | "outputEncoding" => "UTF-8",
+                "language" => "*",
+                "startIndex" | "startPage" => "1",
+                "count" => "20",
+                _ => "",
+            }
+            .to_string()
+        })
+        .to_string()
+}
+
+/// The URL templates of the description by type, from
+/// `<Url type="..." template="...">` elements with their `<Param>`s.
+fn url_templates(xml: &str) -> HashMap<String, String> {
+    static URL: OnceLock<Regex> = OnceLock::new();
+    static PARAM: OnceLock<Regex> = OnceLock::new();
+    let url = cached(
+        &URL,
+        r"(?s)<(?:\w+:)?Url\b([^>]*?)(?:/>|>(.*?)</(?:\w+:)?Url>)",
+    );
+    let param = cached(&PARAM, r"<(?:\w+:)?Param\b([^>]*)>");
+    let mut templates = HashMap::new();
+
+    for captures in url.captures_iter(xml) {
+        let url_attributes = attributes(&captures[1]);
+        let method = url_attributes.get("method").map(|m| m.to_lowercase());
+        if method.is_some_and(|method| method != "get") {
+            continue;
+        }
+        let (kind, mut template) =
+            match (url_attributes.get("type"), url_attributes.get("template")) {
+                (Some(kind), Some(template)) => (kind.clone(), template.clone()),
+                _ => continue,
+            };
+
+        if let Some(body) = captures.get(2) {
+            for param in param.captures_iter(body.as_str()) {
+                let param = attributes(&param[1]);
+                let (name, value) = match (param.get("name"), param.get("value")) {
+                    (Some(name), Some(value)) => (name, value),
+                    _ => continue,
+                };
+                // Optional parameters are left out altogether.
+                if value.starts_with('{') && value.ends_with("?}") {
+                    continue;
+                }
+                template.push(if template.contains('?') { '&' } else { '?' });
+                template.push_str(&format!("{}={}", name, value));
+            }
+        }
+
+        let template = fill_parameters(&template);
+        let is_web = url::Url::parse(&template)
+            .is_ok_and(|url| url.scheme() == "http" || url.scheme() == "https");
+        if is_web && template.contains("{searchTerms}") {
+            templates.entry(kind).or_insert(template);
+        }
+    }
+
+    templates
+}
+
+/// The text of the first element `element` matches.
+fn element_text(xml: &str, element: &Regex) -> Option<String> {
+    element
+        .captures(xml)
+        .map(|captures| unescape_xml(captures[1].trim()))
+        .filter(|text| !text.is_empty())
+}
+
+/// The engine an OpenSearch description describes, or `None` if it has no
+/// usable results URL.
+pub fn parse(xml: &str, description_url: &str) -> Option<SearchEngine> {
+    static SHORT_NAME: OnceLock<Regex> = OnceLock::new();
+    static IMAGE: OnceLock<Regex> = OnceLock::new();
+    let short_name = cached(
+        &SHORT_NAME,
+        r"(?s)<(?:\w+:)?ShortName\b[^>]*>(.*?)</(?:\w+:)?ShortName>",
+    );
+    let image = cached(&IMAGE, r"(?s)<(?:\w+:)?Image\b[^>]*>(.*?)</(?:\w+:)?Image>");
+
+    let name = element_text(xml, short_name)?;
+    let templates = url_templates(xml);
+
+    Some(SearchEngine {
+        name,
+        search_url: templates.get("text/html")?.clone(),
+        suggest_url: templates.get("application/x-suggestions+json").cloned(),
+        keyword: String::new(),
+        bundled: false,
+        icon_url: element_text(xml, image),
+        description_url: Some(description_url.to_string()),
+    })
+}
+
+/// Keeps a copy of the engine's icon for the engine list. Icons are often
+/// `data:` URLs, which are decoded instead.
+fn save_icon(engine: &SearchEngine) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (icon_url, path) = match (&engine.icon_url, engine.icon_path()) {
+        (Some(icon_url), Some(path)) => (icon_url, path),
+        _ => return Ok(()),
+    };
+
+    let data = match icon_url
+        .strip_prefix("data:")
+        .and_then(|data| data.split_once(";base64,"))
+    {
+        Some((_, encoded)) => glib::base64_decode(encoded),
+        None => reqwest::blocking::get(icon_url)?
+            .error_for_status()?
+            .bytes()?
+            .to_vec(),
+    };
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, data)?;
+    Ok(())
+}
+
+fn fetch(link: &SearchLink) -> Result<SearchEngine, Box<dyn Error + Send + Sync>> {
+    let xml = reqwest::blocking::get(&link.href)?
+        .error_for_status()?
+        .text()?;
+    let engine = parse(&xml, &link.href).ok_or("not a usable OpenSearch description")?;
+
+    // The engine works without its icon.
+    if let Err(err) = save_icon(&engine) {
+        eprintln!("Failed to save the icon of {}: {}", engine.name, err);
+    }
+    Ok(engine)
+}
+
+/// Whether the engine behind `link` was added already.
+pub fn is_added(link: &SearchLink) -> bool {
+    Settings::load()
+        .search_engines
+        .iter()
+        .any(|engine| engine.description_url.as_deref() == Some(link.href.as_str()))
+}
+
+/// Fetches the description behind `link` and adds its engine to the
+/// settings. `on_done` gets the engine's name, or why it couldn't be added.
+pub fn add_engine<F>(link: &SearchLink, on_done: F)
+where
+    F: FnOnce(Result<String, String>) + 'static,
+{
+    let (sender, receiver) = MainContext::channel(glib::Priority::DEFAULT);
+
+    thread::spawn({
+        let link = link.clone();
+
+        move || {
+            let result = fetch(&link).map_err(|err| err.to_string());
+            sender.send(result).ok();
+        }
+    });
+
+    let mut on_done = Some(on_done);
+    receiver.attach(None, move |result: Result<SearchEngine, String>| {
+        let result = result.and_then(|engine| {
+            let mut settings = Settings::load();
+            if settings
+                .search_engines
+                .iter()
+                .any(|e| e.name == engine.name)
+            {
+                return Err(format!("there already is an engine called {}", engine.name));
+            }
+
+            let name = engine.name.clone();
+            settings.search_engines.push(engine);
+            settings.save();
+            Ok(name)
+        });
+
+        if let Some(on_done) = on_done.take() {
+            on_done(result);
+        }
+        ControlFlow::Break
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCRIPTION_URL: &str = "https://example.com/opensearch.xml";
+
+    fn description(body: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/">
+  <ShortName>Example</ShortName>
+  {}
+</OpenSearchDescription>"#,
+            body
+        )
+    }
+
+    fn search_url(body: &str) -> Option<String> {
+        parse(&description(body), DESCRIPTION_URL).map(|engine| engine.search_url)
+    }
+
+    #[test]
+    fn parses_namespaced_description() {
+        let xml = r#"<?xml version="1.0"?>
+<os:OpenSearchDescription xmlns:os="http://a9.com/-/spec/opensearch/1.1/">
+  <os:ShortName>Example</os:ShortName>
+  <os:Image width="16" height="16" type="image/x-icon">https://example.com/favicon.ico</os:Image>
+  <os:Url type="text/html" method="get" template="https://example.com/search?q={searchTerms}"/>
+  <os:Url type="application/x-suggestions+json" template="https://example.com/suggest?q={searchTerms}"/>
+</os:OpenSearchDescription>"#;
+
+        let engine = parse(xml, DESCRIPTION_URL).unwrap();
+        assert_eq!(engine.name, "Example");
+        assert_eq!(
+            engine.search_url,
+            "https://example.com/search?q={searchTerms}"
+        );
+        assert_eq!(
+            engine.suggest_url.as_deref(),
+            Some("https://example.com/suggest?q={searchTerms}")
+        );
+        assert_eq!(
+            engine.icon_url.as_deref(),
+            Some("https://example.com/favicon.ico")
+        );
+        assert_eq!(engine.description_url.as_deref(), Some(DESCRIPTION_URL));
+        assert!(engine.keyword.is_empty());
+        assert!(!engine.bundled);
+    }
+
+    #[test]
+    fn fills_parameters() {
+        let cases = [
+            // Params are appended to the template.
+            (
+                r#"<Url type="text/html" template="https://example.com/search">
+                    <Param name="q" value="{searchTerms}"/>
+                    <Param name="lang" value="{language}"/>
+                    <Param name="src" value="abrw"/>
+                </Url>"#,
+                "https://example.com/search?q={searchTerms}&lang=*&src=abrw",
+            ),
+            (
+                r#"<os:Url type="text/html" template="https://example.com/s?x=1">
+                    <os:Param name="q" value="{searchTerms}"/>
+                </os:Url>"#,
+                "https://example.com/s?x=1&q={searchTerms}",
+            ),
+            // Optional Params are left out, optional parameters are empty.
+            (
+                r#"<Url type="text/html" template="https://example.com/search">
+                    <Param name="q" value="{searchTerms}"/>
+                    <Param name="start" value="{startIndex?}"/>
+                </Url>"#,
+                "https://example.com/search?q={searchTerms}",
+            ),
+            (
+                r#"<Url type="text/html" template="https://example.com/?q={searchTerms}&amp;p={startPage?}&amp;n={count}&amp;ie={inputEncoding}"/>"#,
+                "https://example.com/?q={searchTerms}&p=&n=20&ie=UTF-8",
+            ),
+        ];
+
+        for (body, expected) in cases {
+            assert_eq!(search_url(body).as_deref(), Some(expected), "{}", body);
+        }
+    }
+
+    #[test]
+    fn skips_post_urls() {
+        let body = r#"
+            <Url type="text/html" method="POST" template="https://example.com/post?q={searchTerms}"/>
+            <Url type="text/html" method="get" template="https://example.com/get?q={searchTerms}"/>"#;
+        assert_eq!(
+            search_url(body).as_deref(),
+            Some("https://example.com/get?q={searchTerms}")
+        );
+
+        let body = r#"<Url type="text/html" method="post" template="https://example.com/?q={searchTerms}"/>"#;
+        assert_eq!(search_url(body), None);
+    }
+
+    #[test]
+    fn unescapes_entities() {
+        let xml = r#"<OpenSearchDescription>
+  <ShortName>&lt;Tom &amp; Jerry&gt; &quot;search&quot; &apos;s &amp;amp;</ShortName>
+  <Url type='text/html' template='https://example.com/?a=1&amp;q={searchTerms}'/>
+</OpenSearchDescription>"#;
+
+        let engine = parse(xml, DESCRIPTION_URL).unwrap();
+        assert_eq!(engine.name, r#"<Tom & Jerry> "search" 's &amp;"#);
+        assert_eq!(
+            engine.search_url,
+            "https://example.com/?a=1&q={searchTerms}"
+        );
+    }
+
+    #[test]
+    fn rejects_unusable_descriptions() {
+        let cases = [
+            // No results page, only suggestions.
+            r#"<Url type="application/x-suggestions+json" template="https://example.com/s?q={searchTerms}"/>"#,
+            // No place for the query.
+            r#"<Url type="text/html" template="https://example.com/search"/>"#,
+            // Not a web URL.
+            r#"<Url type="text/html" template="javascript:alert('{searchTerms}')"/>"#,
+            // No template at all.
+            r#"<Url type="text/html"/>"#,
+            "",
+        ];
+
+        for body in cases {
+            assert_eq!(search_url(body), None, "{}", body);
+        }
+
+        let unnamed = r#"<OpenSearchDescription>
+  <Url type="text/html" template="https://example.com/?q={searchTerms}"/>
+</OpenSearchDescription>"#;
+        assert!(parse(unnamed, DESCRIPTION_URL).is_none());
+    }
+}
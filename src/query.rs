@@ -0,0 +1,337 @@
+//! Building search URLs from user text.
+//!
+//! Every path that puts a query into a URL template goes through here, so
+//! `&`, `#`, `%`, `+` and non-ASCII text arrive at the engine as typed. Known
+//! engines also get the region and safe search parameters from the settings.
+
+use crate::settings::Settings;
+use serde::{Deserialize, Serialize};
+use url::{form_urlencoded, Url};
+
+/// Placeholder for the query in the URL templates, as in OpenSearch.
+pub const SEARCH_TERMS: &str = "{searchTerms}";
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SafeSearch {
+    /// Leaves it to the engine.
+    #[default]
+    EngineDefault,
+    Off,
+    Moderate,
+    Strict,
+}
+
+impl SafeSearch {
+    pub const ALL: [SafeSearch; 4] = [
+        SafeSearch::EngineDefault,
+        SafeSearch::Off,
+        SafeSearch::Moderate,
+        SafeSearch::Strict,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SafeSearch::EngineDefault => "Engine default",
+            SafeSearch::Off => "Off",
+            SafeSearch::Moderate => "Moderate",
+            SafeSearch::Strict => "Strict",
+        }
+    }
+}
+
+/// Engine parameters that aren't part of the query itself.
+#[derive(Debug, Default, Clone)]
+pub struct QueryOptions {
+    /// A locale such as `en-US`; empty leaves it to the engine.
+    pub region: String,
+    pub safe_search: SafeSearch,
+}
+
+impl QueryOptions {
+    pub fn from_settings(settings: &Settings) -> QueryOptions {
+        QueryOptions {
+            region: settings.search_region.clone(),
+            safe_search: settings.safe_search,
+        }
+    }
+
+    /// The region as lowercase (language, country), from `en-US` or `en_US`.
+    fn language_and_country(&self) -> Option<(String, String)> {
+        let (language, country) = self.region.trim().split_once(['-', '_'])?;
+        let valid = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphabetic());
+        if !valid(language) || !valid(country) {
+            return None;
+        }
+
+        Some((language.to_lowercase(), country.to_lowercase()))
+    }
+}
+
+/// `text` encoded for a query string. Spaces become `+`, as in forms.
+pub fn encode_query(text: &str) -> String {
+    form_urlencoded::byte_serialize(text.as_bytes()).collect()
+}
+
+/// `text` encoded as a path segment. Spaces become `%20`: after form
+/// encoding a `+` can only stand for a space, a literal one is `%2B`.
+pub fn encode_path(text: &str) -> String {
+    encode_query(text).replace('+', "%20")
+}
+
+/// `template` with the encoded `query` in place of `{searchTerms}`, encoded
+/// for the part of the URL the placeholder is in.
+pub fn fill_template(template: &str, query: &str) -> String {
+    let position = match template.find(SEARCH_TERMS) {
+        Some(position) => position,
+        None => return template.to_string(),
+    };
+
+    let encoded = if template[..position].contains(['?', '#']) {
+        encode_query(query)
+    } else {
+        encode_path(query)
+    };
+    template.replace(SEARCH_TERMS, &encoded)
+}
+
+fn is_on(host: &str, domain: &str) -> bool {
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+/// The region and safe search parameters `host` understands.
+fn engine_params(host: &str, options: &QueryOptions) -> Vec<(&'static str, String)> {
+    let region = options.language_and_country();
+    let mut params = Vec::new();
+
+    if is_on(host, "duckduckgo.com") {
+        if let Some((language, country)) = region {
+            params.push(("kl", format!("{}-{}", country, language)));
+        }
+        match options.safe_search {
+            SafeSearch::EngineDefault => {}
+            SafeSearch::Off => params.push(("kp", "-2".to_string())),
+            SafeSearch::Moderate => params.push(("kp", "-1".to_string())),
+            SafeSearch::Strict => params.push(("kp", "1".to_string())),
+        }
+    } else if is_on(host, "google.com") {
+        if let Some((language, country)) = region {
+            params.push(("gl", country));
+            params.push(("hl", language));
+        }
+        match options.safe_search {
+            SafeSearch::EngineDefault => {}
+            SafeSearch::Off => params.push(("safe", "off".to_string())),
+            SafeSearch::Moderate | SafeSearch::Strict => {
+                params.push(("safe", "active".to_string()))
+            }
+        }
+    } else if is_on(host, "bing.com") {
+        if let Some((language, country)) = region {
+            params.push(("cc", country));
+            params.push(("setlang", language));
+        }
+        match options.safe_search {
+            SafeSearch::EngineDefault => {}
+            SafeSearch::Off => params.push(("adlt", "off".to_string())),
+            SafeSearch::Moderate => params.push(("adlt", "moderate".to_string())),
+            SafeSearch::Strict => params.push(("adlt", "strict".to_string())),
+        }
+    }
+
+    params
+}
+
+/// The URL for searching `query` with `template`, plus the engine's
+/// parameters for `options` unless the template sets them itself.
+pub fn build_url(template: &str, query: &str, options: &QueryOptions) -> String {
+    let filled = fill_template(template, query);
+    let mut url = match Url::parse(&filled) {
+        Ok(url) => url,
+        Err(_) => return filled,
+    };
+
+    let params = engine_params(url.host_str().unwrap_or(""), options)
+        .into_iter()
+        .filter(|(name, _)| !url.query_pairs().any(|(existing, _)| existing == *name))
+        .collect::<Vec<_>>();
+    if params.is_empty() {
+        return filled;
+    }
+
+    url.query_pairs_mut().extend_pairs(params);
+    url.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DDG: &str = "https://duckduckgo.com/?q={searchTerms}";
+
+    fn options(region: &str, safe_search: SafeSearch) -> QueryOptions {
+        QueryOptions {
+            region: region.to_string(),
+            safe_search,
+        }
+    }
+
+    #[test]
+    fn encodes_reserved_characters() {
+        let cases = [
+            ("rust", "rust"),
+            ("rust lang", "rust+lang"),
+            ("a&b=c", "a%26b%3Dc"),
+            ("c#", "c%23"),
+            ("100%", "100%25"),
+            ("c++", "c%2B%2B"),
+            ("1 + 1", "1+%2B+1"),
+            ("a/b?c", "a%2Fb%3Fc"),
+            ("\"quoted\"", "%22quoted%22"),
+            ("", ""),
+        ];
+
+        for (query, encoded) in cases {
+            assert_eq!(
+                fill_template(DDG, query),
+                format!("https://duckduckgo.com/?q={}", encoded),
+                "query {:?}",
+                query
+            );
+        }
+    }
+
+    #[test]
+    fn encodes_non_ascii_as_utf8() {
+        assert_eq!(
+            fill_template(DDG, "häagen dazs"),
+            "https://duckduckgo.com/?q=h%C3%A4agen+dazs"
+        );
+        assert_eq!(
+            fill_template(DDG, "日本"),
+            "https://duckduckgo.com/?q=%E6%97%A5%E6%9C%AC"
+        );
+        assert_eq!(
+            fill_template(DDG, "🦀"),
+            "https://duckduckgo.com/?q=%F0%9F%A6%80"
+        );
+    }
+
+    #[test]
+    fn encodes_path_placeholders_as_path() {
+        let maps = "https://www.google.com/maps/search/{searchTerms}";
+        assert_eq!(
+            fill_template(maps, "new york"),
+            "https://www.google.com/maps/search/new%20york"
+        );
+        assert_eq!(
+            fill_template(maps, "a+b/c"),
+            "https://www.google.com/maps/search/a%2Bb%2Fc"
+        );
+    }
+
+    #[test]
+    fn encodes_fragment_placeholders_as_query() {
+        assert_eq!(
+            fill_template("https://example.com/#q={searchTerms}", "a b&c"),
+            "https://example.com/#q=a+b%26c"
+        );
+    }
+
+    #[test]
+    fn template_without_placeholder_is_kept() {
+        assert_eq!(
+            fill_template("https://example.com/", "rust"),
+            "https://example.com/"
+        );
+    }
+
+    #[test]
+    fn default_options_add_nothing() {
+        assert_eq!(
+            build_url(DDG, "a&b", &QueryOptions::default()),
+            "https://duckduckgo.com/?q=a%26b"
+        );
+    }
+
+    #[test]
+    fn adds_duckduckgo_params() {
+        assert_eq!(
+            build_url(DDG, "rust", &options("en-US", SafeSearch::Strict)),
+            "https://duckduckgo.com/?q=rust&kl=us-en&kp=1"
+        );
+        assert_eq!(
+            build_url(
+                "https://ac.duckduckgo.com/ac/?q={searchTerms}&type=list",
+                "rust",
+                &options("de_DE", SafeSearch::Off)
+            ),
+            "https://ac.duckduckgo.com/ac/?q=rust&type=list&kl=de-de&kp=-2"
+        );
+    }
+
+    #[test]
+    fn adds_google_params() {
+        assert_eq!(
+            build_url(
+                "https://www.google.com/search?q={searchTerms}",
+                "c++",
+                &options("fi-FI", SafeSearch::Moderate)
+            ),
+            "https://www.google.com/search?q=c%2B%2B&gl=fi&hl=fi&safe=active"
+        );
+    }
+
+    #[test]
+    fn adds_bing_params() {
+        assert_eq!(
+            build_url(
+                "https://www.bing.com/search?q={searchTerms}",
+                "rust",
+                &options("en-GB", SafeSearch::Moderate)
+            ),
+            "https://www.bing.com/search?q=rust&cc=gb&setlang=en&adlt=moderate"
+        );
+    }
+
+    #[test]
+    fn keeps_params_set_by_the_template() {
+        assert_eq!(
+            build_url(
+                "https://duckduckgo.com/?kp=-2&q={searchTerms}",
+                "rust",
+                &options("", SafeSearch::Strict)
+            ),
+            "https://duckduckgo.com/?kp=-2&q=rust"
+        );
+    }
+
+    #[test]
+    fn unknown_engines_get_no_params() {
+        let wikipedia = "https://en.wikipedia.org/wiki/Special:Search?search={searchTerms}";
+        assert_eq!(
+            build_url(wikipedia, "a b", &options("en-US", SafeSearch::Strict)),
+            "https://en.wikipedia.org/wiki/Special:Search?search=a+b"
+        );
+        // Only the domain itself and its subdomains count.
+        assert_eq!(
+            build_url(
+                "https://notgoogle.com/?q={searchTerms}",
+                "a",
+                &options("en-US", SafeSearch::Strict)
+            ),
+            "https://notgoogle.com/?q=a"
+        );
+    }
+
+    #[test]
+    fn ignores_malformed_regions() {
+        for region in ["english", "en-", "-US", "en-U5", " "] {
+            assert_eq!(
+                build_url(DDG, "rust", &options(region, SafeSearch::EngineDefault)),
+                "https://duckduckgo.com/?q=rust",
+                "region {:?}",
+                region
+            );
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Requests checked in each tab, as reported by the web process extension.
+
+use gtk::prelude::*;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use webkit2gtk::{LoadEvent, UserMessage, UserMessageExt, WebViewExt};
+
+const REQUEST_CHECKED_MESSAGE: &str = "abrw-request-checked";
+
+/// Sent to a tab's page to turn reporting of allowed requests on or off. The
+/// extension only reports blocked and redirected ones otherwise, which is all
+/// the badge needs.
+const LOG_ALLOWED_MESSAGE: &str = "abrw-log-allowed";
+
+/// The log and the blocked counts only see requests that reach the web
+/// process extension's request filter; WebKit's content blocker stops most of
+/// them earlier.
+pub const CONTENT_BLOCKER_NOTE: &str =
+    "Only requests checked by the request filter are counted and logged; \
+     those WebKit's content blocker stops first aren't.";
+
+/// The blocked count as shown to the user, saying what it counts.
+pub fn blocked_text(blocked: usize) -> String {
+    match blocked {
+        1 => "1 request blocked by the request filter".to_string(),
+        n => format!("{} requests blocked by the request filter", n),
+    }
+}
+
+/// Older entries are dropped past this, so a long running page can't grow the
+/// log forever.
+const MAX_ENTRIES: usize = 5000;
+
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub url: String,
+    pub request_type: String,
+    pub blocked: bool,
+    /// The rule that matched, if any.
+    pub filter: Option<String>,
+    /// The `@@` rule that let a matched request through.
+    pub exception: Option<String>,
+    pub redirected: bool,
+}
+
+impl LogEntry {
+    fn from_variant(parameters: &gtk::glib::Variant) -> Option<LogEntry> {
+        let (url, request_type, blocked, filter, exception, redirected) =
+            parameters.get::<(String, String, bool, String, String, bool)>()?;
+
+        Some(LogEntry {
+            url,
+            request_type,
+            blocked,
+            filter: Some(filter).filter(|filter| !filter.is_empty()),
+            exception: Some(exception).filter(|exception| !exception.is_empty()),
+            redirected,
+        })
+    }
+}
+
+/// Called with each new entry of a tab, or `None` when the tab navigated and
+/// its log was cleared.
+type Listener = Box<dyn Fn(&webkit2gtk::WebView, Option<&LogEntry>)>;
+
+#[derive(Default)]
+struct TabLog {
+    entries: VecDeque<LogEntry>,
+    blocked: usize,
+}
+
+/// Log of the page currently shown in each tab.
+#[derive(Clone, Default)]
+pub struct RequestLog {
+    tabs: Rc<RefCell<HashMap<webkit2gtk::WebView, TabLog>>>,
+    listeners: Rc<RefCell<HashMap<usize, Listener>>>,
+    next_listener: Rc<Cell<usize>>,
+    /// Log windows open for each tab, which want allowed requests too.
+    logging_allowed: Rc<RefCell<HashMap<webkit2gtk::WebView, usize>>>,
+}
+
+fn send_log_allowed(webview: &webkit2gtk::WebView, enabled: bool) {
+    let message = UserMessage::new(LOG_ALLOWED_MESSAGE, Some(&enabled.to_variant()));
+    webview.send_message_to_page(&message, gtk::gio::Cancellable::NONE, |_| {});
+}
+
+fn set_badge(badge: &gtk::Label, blocked: usize) {
+    badge.set_text(&blocked.to_string());
+    badge.set_tooltip_text(Some(&format!(
+        "{}\n{}",
+        blocked_text(blocked),
+        CONTENT_BLOCKER_NOTE
+    )));
+    badge.set_visible(blocked > 0);
+}
+
+impl RequestLog {
+    /// Starts logging `webview`, keeping the blocked count in `badge`.
+    pub fn watch(&self, webview: &webkit2gtk::WebView, badge: &gtk::Label) {
+        self.tabs
+            .borrow_mut()
+            .insert(webview.clone(), TabLog::default());
+        set_badge(badge, 0);
+
+        webview.connect_user_message_received({
+            let log = self.clone();
+            let badge = badge.clone();
+
+            move |webview, message| {
+                if message.name().as_deref() != Some(REQUEST_CHECKED_MESSAGE) {
+                    return false;
+                }
+
+                let entry = match message
+                    .parameters()
+                    .as_ref()
+                    .and_then(LogEntry::from_variant)
+                {
+                    Some(entry) => entry,
+                    None => return true,
+                };
+
+                if let Some(tab) = log.tabs.borrow_mut().get_mut(webview) {
+                    if entry.blocked {
+                        tab.blocked += 1;
+                        set_badge(&badge, tab.blocked);
+                    }
+                    if tab.entries.len() >= MAX_ENTRIES {
+                        tab.entries.pop_front();
+                    }
+                    tab.entries.push_back(entry.clone());
+                }
+
+                log.notify(webview, Some(&entry));
+                true
+            }
+        });
+
+        webview.connect_load_changed({
+            let log = self.clone();
+            let badge = badge.clone();
+
+            move |webview, event| {
+                // A navigation can move the tab to a new web process, whose
+                // extension hasn't been told yet.
+                if matches!(event, LoadEvent::Started | LoadEvent::Committed)
+                    && log.logging_allowed.borrow().contains_key(webview)
+                {
+                    send_log_allowed(webview, true);
+                }
+                if event != LoadEvent::Started {
+                    return;
+                }
+
+                if let Some(tab) = log.tabs.borrow_mut().get_mut(webview) {
+                    *tab = TabLog::default();
+                }
+                set_badge(&badge, 0);
+                log.notify(webview, None);
+            }
+        });
+    }
+
+    fn notify(&self, webview: &webkit2gtk::WebView, entry: Option<&LogEntry>) {
+        for listener in self.listeners.borrow().values() {
+            listener(webview, entry);
+        }
+    }
+
+    /// Stops tracking a tab that was closed.
+    pub fn forget(&self, webview: &webkit2gtk::WebView) {
+        self.tabs.borrow_mut().remove(webview);
+        self.logging_allowed.borrow_mut().remove(webview);
+    }
+
+    /// Has the extension report allowed requests of `webview` too, until
+    /// `stop_logging_allowed` is called as often.
+    pub fn log_allowed(&self, webview: &webkit2gtk::WebView) {
+        *self
+            .logging_allowed
+            .borrow_mut()
+            .entry(webview.clone())
+            .or_default() += 1;
+        send_log_allowed(webview, true);
+    }
+
+    pub fn stop_logging_allowed(&self, webview: &webkit2gtk::WebView) {
+        let mut logging_allowed = self.logging_allowed.borrow_mut();
+        if let Some(windows) = logging_allowed.get_mut(webview) {
+            *windows -= 1;
+            if *windows == 0 {
+                logging_allowed.remove(webview);
+                send_log_allowed(webview, false);
+            }
+        }
+    }
+
+    pub fn blocked(&self, webview: &webkit2gtk::WebView) -> usize {
+        self.tabs
+            .borrow()
+            .get(webview)
+            .map(|tab| tab.blocked)
+            .unwrap_or(0)
+    }
+
+    pub fn entries(&self, webview: &webkit2gtk::WebView) -> Vec<LogEntry> {
+        self.tabs
+            .borrow()
+            .get(webview)
+            .map(|tab| tab.entries.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Registers a listener for new entries; pass the returned id to
+    /// `unsubscribe` to remove it.
+    pub fn subscribe<F: Fn(&webkit2gtk::WebView, Option<&LogEntry>) + 'static>(
+        &self,
+        listener: F,
+    ) -> usize {
+        let id = self.next_listener.get();
+        self.next_listener.set(id + 1);
+        self.listeners.borrow_mut().insert(id, Box::new(listener));
+        id
+    }
+
+    pub fn unsubscribe(&self, id: usize) {
+        self.listeners.borrow_mut().remove(&id);
+    }
+}
@@ -1,11 +1,129 @@
+use crate::query::{self, QueryOptions};
+use crate::settings::Settings;
+use crate::utils;
 use reqwest::blocking::get;
-
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::io;
+use std::path::PathBuf;
+
+/// A search engine the URL bar can send queries to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchEngine {
+    pub name: String,
+    /// Results page, with `{searchTerms}` where the query goes.
+    pub search_url: String,
+    /// OpenSearch suggestions endpoint, if the engine has one.
+    pub suggest_url: Option<String>,
+    /// Typing `keyword query` searches this engine.
+    pub keyword: String,
+    /// Shipped with abrw; can't be removed.
+    pub bundled: bool,
+    /// Icon from the engine's OpenSearch description.
+    #[serde(default)]
+    pub icon_url: Option<String>,
+    /// The OpenSearch description the engine was added from.
+    #[serde(default)]
+    pub description_url: Option<String>,
+}
+
+/// Engines offered out of the box, as (name, search URL, suggestion URL,
+/// keyword).
+pub const BUNDLED_ENGINES: &[(&str, &str, Option<&str>, &str)] = &[
+    (
+        "DuckDuckGo",
+        "https://duckduckgo.com/?q={searchTerms}",
+        Some("https://ac.duckduckgo.com/ac/?q={searchTerms}&type=list"),
+        "d",
+    ),
+    (
+        "Google",
+        "https://www.google.com/search?q={searchTerms}",
+        Some("https://suggestqueries.google.com/complete/search?client=firefox&q={searchTerms}"),
+        "g",
+    ),
+    (
+        "Wikipedia",
+        "https://en.wikipedia.org/wiki/Special:Search?search={searchTerms}",
+        Some("https://en.wikipedia.org/w/api.php?action=opensearch&search={searchTerms}"),
+        "w",
+    ),
+    (
+        "GitHub",
+        "https://github.com/search?q={searchTerms}",
+        None,
+        "gh",
+    ),
+];
+
+pub const DEFAULT_ENGINE: &str = "DuckDuckGo";
+
+impl SearchEngine {
+    /// Where the icon is kept once `opensearch` has downloaded it.
+    pub fn icon_path(&self) -> Option<PathBuf> {
+        let icon_url = self.icon_url.as_ref()?;
+        let mut hasher = DefaultHasher::new();
+        icon_url.hash(&mut hasher);
+        Some(
+            utils::data_dir()
+                .join("engine-icons")
+                .join(format!("{:016x}", hasher.finish())),
+        )
+    }
+
+    pub fn search_url(&self, query: &str, options: &QueryOptions) -> String {
+        query::build_url(&self.search_url, query, options)
+    }
+
+    pub fn suggest_url(&self, query: &str, options: &QueryOptions) -> Option<String> {
+        self.suggest_url
+            .as_ref()
+            .map(|template| query::build_url(template, query, options))
+    }
 
-pub fn fetch_suggestions(query: &str) -> Result<Value, io::Error> {
-    let url = format!("https://ac.duckduckgo.com/ac/?q={}", query);
+    /// The site the engine lives on, used as the start page.
+    pub fn home_url(&self) -> String {
+        url::Url::parse(&self.search_url)
+            .map(|url| url.origin().ascii_serialization() + "/")
+            .unwrap_or_else(|_| self.search_url.clone())
+    }
+}
+
+/// The engine and query for `text`: `keyword query` goes to the engine with
+/// that keyword, anything else to the default engine.
+pub fn resolve<'a>(settings: &'a Settings, text: &'a str) -> (&'a SearchEngine, &'a str) {
+    if let Some((keyword, query)) = text.trim().split_once(' ') {
+        let query = query.trim();
+        if !query.is_empty() {
+            if let Some(engine) = settings.search_engine_by_keyword(keyword) {
+                return (engine, query);
+            }
+        }
+    }
+
+    (settings.default_search_engine(), text.trim())
+}
+
+/// The search results URL for `text`, honouring keywords.
+pub fn search_url(text: &str) -> String {
+    let settings = Settings::load();
+    let (engine, query) = resolve(&settings, text);
+    engine.search_url(query, &QueryOptions::from_settings(&settings))
+}
+
+/// Suggestions in the OpenSearch format: `[query, [completion, ...], ...]`.
+/// Suggestions for a keyword search keep the keyword in front.
+pub fn fetch_suggestions(text: &str) -> Result<Vec<String>, io::Error> {
+    let settings = Settings::load();
+    let (engine, query) = resolve(&settings, text);
+    let keyword_prefix = &text.trim()[..text.trim().len() - query.len()];
+    let url = match engine.suggest_url(query, &QueryOptions::from_settings(&settings)) {
+        Some(url) => url,
+        None => return Ok(Vec::new()),
+    };
 
     let response = get(&url)
         .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
@@ -15,5 +133,17 @@ pub fn fetch_suggestions(query: &str) -> Result<Value, io::Error> {
     let json: Value =
         serde_json::from_str(&response).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
-    Ok(json)
+    let suggestions = json
+        .get(1)
+        .and_then(Value::as_array)
+        .map(|suggestions| {
+            suggestions
+                .iter()
+                .filter_map(Value::as_str)
+                .map(|suggestion| format!("{}{}", keyword_prefix, suggestion))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(suggestions)
 }
@@ -1,11 +1,28 @@
-use gtk::{glib::Propagation, Box, Button, Label, Orientation, Switch, Window, WindowType};
+use crate::adblock_abrw::{self, ListUpdater};
+use crate::bangs::Bang;
+use crate::query::SafeSearch;
+use crate::search::{self, SearchEngine};
+use gtk::{
+    gdk_pixbuf::Pixbuf, glib::Propagation, Box, Button, ComboBoxText, Entry, Image, Label,
+    Orientation, ScrolledWindow, Switch, TextView, Window, WindowType,
+};
 use gtk::{prelude::*, STYLE_PROVIDER_PRIORITY_APPLICATION};
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
 use std::fs;
-use std::rc::Rc;
+use webkit2gtk::WebContext;
+
+/// A filter list subscription. `location` is a URL or a local file path.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FilterList {
+    pub name: String,
+    pub location: String,
+    pub enabled: bool,
+    /// Shipped with abrw; can be disabled but not removed.
+    pub bundled: bool,
+}
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
 pub struct Settings {
     pub private_browsing: bool,
     pub enable_javascript: bool,
@@ -17,6 +34,21 @@ pub struct Settings {
     pub enable_local_storage: bool,
     pub enable_indexed_db: bool,
     pub media_playback_requires_user_gesture: bool,
+    /// Let pages open windows without a click or key press.
+    pub allow_popups: bool,
+    /// Upgrade every http request to https.
+    pub https_only: bool,
+    pub filter_lists: Vec<FilterList>,
+    /// Rules typed into "My filters", one per line.
+    pub user_filters: String,
+    pub search_engines: Vec<SearchEngine>,
+    /// Name of the engine used for queries without a keyword.
+    pub default_engine: String,
+    /// Locale such as `en-US` sent to engines that support it.
+    pub search_region: String,
+    pub safe_search: SafeSearch,
+    /// User defined `!bang` shortcuts, on top of the bundled ones.
+    pub bangs: Vec<Bang>,
 }
 
 impl Settings {
@@ -26,16 +58,276 @@ impl Settings {
     }
 
     pub fn load() -> Settings {
-        if let Ok(data) = fs::read_to_string("settings.json") {
+        let mut settings = if let Ok(data) = fs::read_to_string("settings.json") {
             serde_json::from_str(&data).unwrap_or_default()
         } else {
             Settings::default()
+        };
+
+        settings.add_bundled_lists();
+        settings.add_bundled_engines();
+        settings
+    }
+
+    fn add_bundled_engines(&mut self) {
+        for (name, search_url, suggest_url, keyword) in search::BUNDLED_ENGINES {
+            if !self
+                .search_engines
+                .iter()
+                .any(|engine| engine.name == *name)
+            {
+                self.search_engines.push(SearchEngine {
+                    name: name.to_string(),
+                    search_url: search_url.to_string(),
+                    suggest_url: suggest_url.map(str::to_string),
+                    keyword: keyword.to_string(),
+                    bundled: true,
+                    icon_url: None,
+                    description_url: None,
+                });
+            }
+        }
+
+        if self.default_engine.is_empty() {
+            self.default_engine = search::DEFAULT_ENGINE.to_string();
+        }
+    }
+
+    pub fn default_search_engine(&self) -> &SearchEngine {
+        self.search_engines
+            .iter()
+            .find(|engine| engine.name == self.default_engine)
+            .unwrap_or(&self.search_engines[0])
+    }
+
+    pub fn search_engine_by_keyword(&self, keyword: &str) -> Option<&SearchEngine> {
+        self.search_engines
+            .iter()
+            .find(|engine| !engine.keyword.is_empty() && engine.keyword == keyword)
+    }
+
+    /// Lists shipped with abrw start out enabled, including ones added in a
+    /// later version.
+    fn add_bundled_lists(&mut self) {
+        for (name, url) in adblock_abrw::BUNDLED_LISTS {
+            if !self.filter_lists.iter().any(|list| list.location == *url) {
+                self.filter_lists.push(FilterList {
+                    name: name.to_string(),
+                    location: url.to_string(),
+                    enabled: true,
+                    bundled: true,
+                });
+            }
+        }
+    }
+
+    /// Loads the settings, applies `change` and saves them. A copy kept
+    /// around, like the settings window's, would overwrite changes made
+    /// meanwhile, such as a subscription accepted from a page.
+    pub fn update<R>(change: impl FnOnce(&mut Settings) -> R) -> R {
+        let mut settings = Settings::load();
+        let result = change(&mut settings);
+        settings.save();
+        result
+    }
+
+    /// Adds an enabled subscription. Returns `false` if it already exists.
+    pub fn subscribe(&mut self, name: &str, location: &str) -> bool {
+        if self
+            .filter_lists
+            .iter()
+            .any(|list| list.location == location)
+        {
+            return false;
         }
+
+        self.filter_lists.push(FilterList {
+            name: name.to_string(),
+            location: location.to_string(),
+            enabled: true,
+            bundled: false,
+        });
+        true
     }
 }
 
-pub fn show_settings_window() {
-    let settings = Rc::new(RefCell::new(Settings::load())); // Load settings from file
+/// Rebuilds the rows of the "Filter lists" section.
+fn fill_filter_lists(lists_box: &Box, list_updater: &ListUpdater) {
+    for child in lists_box.children() {
+        lists_box.remove(&child);
+    }
+
+    let stats = adblock_abrw::list_stats();
+
+    for list in Settings::load().filter_lists {
+        let hbox = Box::new(Orientation::Horizontal, 5);
+        let list_label = Label::new(Some(&list.name));
+        list_label.set_tooltip_text(Some(&list.location));
+        list_label.set_halign(gtk::Align::Start);
+
+        let name_box = Box::new(Orientation::Vertical, 0);
+        name_box.pack_start(&list_label, false, false, 0);
+        if let Some(stats) = stats.get(&list.location).filter(|_| list.enabled) {
+            let mut summary = format!("{} rules ({})", stats.rules, stats.format.name());
+            if stats.errors + stats.unsupported > 0 {
+                summary.push_str(&format!(", {} skipped", stats.errors + stats.unsupported));
+            }
+            let stats_label = Label::new(Some(&summary));
+            stats_label.set_tooltip_text(Some(&format!(
+                "{} unsupported lines, {} parse errors",
+                stats.unsupported, stats.errors
+            )));
+            stats_label.set_halign(gtk::Align::Start);
+            stats_label.style_context().add_class("dim-label");
+            name_box.pack_start(&stats_label, false, false, 0);
+        }
+
+        let switch = Switch::new();
+        switch.set_active(list.enabled);
+
+        hbox.pack_start(&name_box, true, true, 0);
+        hbox.pack_end(&switch, false, false, 0);
+
+        switch.connect_active_notify({
+            let location = list.location.clone();
+            let list_updater = list_updater.clone();
+
+            move |switch| {
+                Settings::update(|settings| {
+                    for list in &mut settings.filter_lists {
+                        if list.location == location {
+                            list.enabled = switch.is_active();
+                        }
+                    }
+                });
+                list_updater.update();
+            }
+        });
+
+        if !list.bundled {
+            let remove_button = Button::with_label("Remove");
+            hbox.pack_end(&remove_button, false, false, 0);
+
+            remove_button.connect_clicked({
+                let lists_box = lists_box.clone();
+                let location = list.location.clone();
+                let list_updater = list_updater.clone();
+
+                move |_| {
+                    Settings::update(|settings| {
+                        settings
+                            .filter_lists
+                            .retain(|list| list.location != location)
+                    });
+                    list_updater.update();
+                    fill_filter_lists(&lists_box, &list_updater);
+                }
+            });
+        }
+
+        lists_box.pack_start(&hbox, false, false, 0);
+    }
+
+    lists_box.show_all();
+}
+
+/// Rebuilds the rows of the "Search engines" section and the default engine
+/// selector.
+fn fill_search_engines(engines_box: &Box, default_combo: &ComboBoxText) {
+    for child in engines_box.children() {
+        engines_box.remove(&child);
+    }
+    default_combo.remove_all();
+
+    let settings = Settings::load();
+    for engine in &settings.search_engines {
+        default_combo.append(Some(&engine.name), &engine.name);
+
+        let hbox = Box::new(Orientation::Horizontal, 5);
+        let icon = Image::new();
+        icon.set_size_request(16, 16);
+        if let Some(pixbuf) = engine
+            .icon_path()
+            .and_then(|path| Pixbuf::from_file_at_scale(path, 16, 16, true).ok())
+        {
+            icon.set_from_pixbuf(Some(&pixbuf));
+        }
+        let engine_label = Label::new(Some(&engine.name));
+        engine_label.set_tooltip_text(Some(&engine.search_url));
+        engine_label.set_halign(gtk::Align::Start);
+        let keyword_label = Label::new(Some(&engine.keyword));
+        keyword_label.style_context().add_class("dim-label");
+
+        hbox.pack_start(&icon, false, false, 0);
+        hbox.pack_start(&engine_label, true, true, 0);
+        hbox.pack_end(&keyword_label, false, false, 0);
+
+        if !engine.bundled {
+            let remove_button = Button::with_label("Remove");
+            hbox.pack_end(&remove_button, false, false, 0);
+
+            remove_button.connect_clicked({
+                let engines_box = engines_box.clone();
+                let default_combo = default_combo.clone();
+                let name = engine.name.clone();
+
+                move |_| {
+                    Settings::update(|settings| {
+                        settings.search_engines.retain(|engine| engine.name != name)
+                    });
+                    fill_search_engines(&engines_box, &default_combo);
+                }
+            });
+        }
+
+        engines_box.pack_start(&hbox, false, false, 0);
+    }
+
+    default_combo.set_active_id(Some(&settings.default_search_engine().name));
+    engines_box.show_all();
+}
+
+/// Rebuilds the rows of the "Bangs" section.
+fn fill_bangs(bangs_box: &Box) {
+    for child in bangs_box.children() {
+        bangs_box.remove(&child);
+    }
+
+    for bang in Settings::load().bangs {
+        let hbox = Box::new(Orientation::Horizontal, 5);
+        let bang_label = Label::new(Some(&format!("!{}", bang.trigger)));
+        bang_label.set_tooltip_text(Some(&bang.url));
+        bang_label.set_halign(gtk::Align::Start);
+        let remove_button = Button::with_label("Remove");
+
+        hbox.pack_start(&bang_label, true, true, 0);
+        hbox.pack_end(&remove_button, false, false, 0);
+
+        remove_button.connect_clicked({
+            let bangs_box = bangs_box.clone();
+            let trigger = bang.trigger.clone();
+
+            move |_| {
+                Settings::update(|settings| settings.bangs.retain(|bang| bang.trigger != trigger));
+                fill_bangs(&bangs_box);
+            }
+        });
+
+        bangs_box.pack_start(&hbox, false, false, 0);
+    }
+
+    bangs_box.show_all();
+}
+
+fn save_region(entry: &Entry) {
+    let region = entry.text().trim().to_string();
+    if Settings::load().search_region != region {
+        Settings::update(|settings| settings.search_region = region);
+    }
+}
+
+pub fn show_settings_window(list_updater: &ListUpdater) {
+    let settings = Settings::load();
 
     let window = Window::new(WindowType::Toplevel);
     window.set_title("Abrw Settings");
@@ -80,18 +372,17 @@ pub fn show_settings_window() {
             let hbox = Box::new(Orientation::Horizontal, 0);
             let setting_label = Label::new(Some(label));
             let switch = Switch::new();
-            let settings_clone = Rc::clone(&settings);
-            switch.set_active(get_value(&settings_clone.borrow()));
+            switch.set_active(get_value(&settings));
 
             hbox.pack_start(&setting_label, true, true, 0);
             hbox.pack_end(&switch, false, false, 0);
             vbox.pack_start(&hbox, false, false, 0);
 
             switch.connect_active_notify(move |switch| {
-                let mut settings = settings_clone.borrow_mut();
-                set_value(&mut settings, switch.is_active());
-                settings.save(); // Save settings to file
+                Settings::update(|settings| set_value(settings, switch.is_active()));
             });
+
+            switch
         };
 
     create_setting(
@@ -140,11 +431,255 @@ pub fn show_settings_window() {
         |s| s.media_playback_requires_user_gesture,
         |s, v| s.media_playback_requires_user_gesture = v,
     );
+    create_setting(
+        "Allow pop-ups",
+        |s| s.allow_popups,
+        |s, v| s.allow_popups = v,
+    );
+    create_setting("HTTPS-only mode", |s| s.https_only, |s, v| s.https_only = v)
+        .connect_active_notify(|_| {
+            adblock_abrw::notify_site_settings_updated(&WebContext::default().unwrap());
+        });
+
+    let engines_label = Label::new(Some("Search engines"));
+    engines_label.set_halign(gtk::Align::Start);
+    vbox.pack_start(&engines_label, false, false, 0);
+
+    let default_box = Box::new(Orientation::Horizontal, 5);
+    let default_combo = ComboBoxText::new();
+    default_box.pack_start(&Label::new(Some("Default")), false, false, 0);
+    default_box.pack_end(&default_combo, false, false, 0);
+    vbox.pack_start(&default_box, false, false, 0);
+
+    let region_box = Box::new(Orientation::Horizontal, 5);
+    let region_entry = Entry::new();
+    region_entry.set_placeholder_text(Some("en-US"));
+    region_entry.set_width_chars(8);
+    region_entry.set_text(&settings.search_region);
+    region_box.pack_start(&Label::new(Some("Region")), false, false, 0);
+    region_box.pack_end(&region_entry, false, false, 0);
+    vbox.pack_start(&region_box, false, false, 0);
+
+    // Saved when done typing, not on every keystroke.
+    region_entry.connect_activate(save_region);
+    region_entry.connect_focus_out_event(|entry, _| {
+        save_region(entry);
+        Propagation::Proceed
+    });
+    window.connect_delete_event({
+        let region_entry = region_entry.clone();
+
+        move |_, _| {
+            save_region(&region_entry);
+            Propagation::Proceed
+        }
+    });
+
+    let safe_search_box = Box::new(Orientation::Horizontal, 5);
+    let safe_search_combo = ComboBoxText::new();
+    for safe_search in SafeSearch::ALL {
+        safe_search_combo.append(Some(safe_search.name()), safe_search.name());
+    }
+    safe_search_combo.set_active_id(Some(settings.safe_search.name()));
+    safe_search_box.pack_start(&Label::new(Some("Safe search")), false, false, 0);
+    safe_search_box.pack_end(&safe_search_combo, false, false, 0);
+    vbox.pack_start(&safe_search_box, false, false, 0);
+
+    safe_search_combo.connect_changed(|combo| {
+        let selected = combo.active_id().and_then(|id| {
+            SafeSearch::ALL
+                .into_iter()
+                .find(|safe_search| safe_search.name() == id.as_str())
+        });
+        if let Some(safe_search) = selected {
+            Settings::update(|settings| settings.safe_search = safe_search);
+        }
+    });
+
+    let engines_box = Box::new(Orientation::Vertical, 5);
+    vbox.pack_start(&engines_box, false, false, 0);
+    fill_search_engines(&engines_box, &default_combo);
+
+    default_combo.connect_changed(|combo| {
+        if let Some(name) = combo.active_id() {
+            if Settings::load().default_engine != name.as_str() {
+                Settings::update(|settings| settings.default_engine = name.to_string());
+            }
+        }
+    });
+
+    let add_engine_box = Box::new(Orientation::Horizontal, 5);
+    let engine_name_entry = Entry::new();
+    engine_name_entry.set_placeholder_text(Some("Name"));
+    let engine_keyword_entry = Entry::new();
+    engine_keyword_entry.set_placeholder_text(Some("Keyword"));
+    engine_keyword_entry.set_width_chars(6);
+    let engine_url_entry = Entry::new();
+    engine_url_entry.set_placeholder_text(Some("https://example.com/?q={searchTerms}"));
+    let add_engine_button = Button::with_label("Add");
+    add_engine_box.pack_start(&engine_name_entry, false, false, 0);
+    add_engine_box.pack_start(&engine_keyword_entry, false, false, 0);
+    add_engine_box.pack_start(&engine_url_entry, true, true, 0);
+    add_engine_box.pack_end(&add_engine_button, false, false, 0);
+    vbox.pack_start(&add_engine_box, false, false, 0);
+
+    add_engine_button.connect_clicked({
+        let engines_box = engines_box.clone();
+        let default_combo = default_combo.clone();
+
+        move |_| {
+            let name = engine_name_entry.text().trim().to_string();
+            let keyword = engine_keyword_entry.text().trim().to_string();
+            let search_url = engine_url_entry.text().trim().to_string();
+            if name.is_empty() || !search_url.contains("{searchTerms}") {
+                return;
+            }
+
+            let added = Settings::update(|settings| {
+                if settings.search_engines.iter().any(|e| e.name == name) {
+                    return false;
+                }
+                settings.search_engines.push(SearchEngine {
+                    name,
+                    search_url,
+                    suggest_url: None,
+                    keyword,
+                    bundled: false,
+                    icon_url: None,
+                    description_url: None,
+                });
+                true
+            });
+            if !added {
+                return;
+            }
+
+            fill_search_engines(&engines_box, &default_combo);
+            engine_name_entry.set_text("");
+            engine_keyword_entry.set_text("");
+            engine_url_entry.set_text("");
+        }
+    });
+
+    let bangs_label = Label::new(Some("Bangs"));
+    bangs_label.set_halign(gtk::Align::Start);
+    vbox.pack_start(&bangs_label, false, false, 0);
+
+    let bangs_box = Box::new(Orientation::Vertical, 5);
+    vbox.pack_start(&bangs_box, false, false, 0);
+    fill_bangs(&bangs_box);
+
+    let add_bang_box = Box::new(Orientation::Horizontal, 5);
+    let bang_trigger_entry = Entry::new();
+    bang_trigger_entry.set_placeholder_text(Some("!trigger"));
+    bang_trigger_entry.set_width_chars(8);
+    let bang_url_entry = Entry::new();
+    bang_url_entry.set_placeholder_text(Some("https://example.com/?q={searchTerms}"));
+    let add_bang_button = Button::with_label("Add");
+    add_bang_box.pack_start(&bang_trigger_entry, false, false, 0);
+    add_bang_box.pack_start(&bang_url_entry, true, true, 0);
+    add_bang_box.pack_end(&add_bang_button, false, false, 0);
+    vbox.pack_start(&add_bang_box, false, false, 0);
+
+    add_bang_button.connect_clicked({
+        let bangs_box = bangs_box.clone();
+
+        move |_| {
+            let text = bang_trigger_entry.text();
+            let trigger = text.trim().trim_start_matches('!').to_lowercase();
+            let url = bang_url_entry.text().trim().to_string();
+            if trigger.is_empty() || trigger.contains(' ') || !url.contains("{searchTerms}") {
+                return;
+            }
+
+            Settings::update(|settings| {
+                settings.bangs.retain(|bang| bang.trigger != trigger);
+                settings.bangs.push(Bang {
+                    name: trigger.clone(),
+                    trigger,
+                    url,
+                });
+            });
+
+            fill_bangs(&bangs_box);
+            bang_trigger_entry.set_text("");
+            bang_url_entry.set_text("");
+        }
+    });
+
+    let lists_label = Label::new(Some("Filter lists"));
+    lists_label.set_halign(gtk::Align::Start);
+    vbox.pack_start(&lists_label, false, false, 0);
+
+    let lists_box = Box::new(Orientation::Vertical, 5);
+    vbox.pack_start(&lists_box, false, false, 0);
+    fill_filter_lists(&lists_box, list_updater);
+
+    let add_box = Box::new(Orientation::Horizontal, 5);
+    let add_entry = Entry::new();
+    add_entry.set_placeholder_text(Some("List URL or file path"));
+    let add_button = Button::with_label("Add");
+    add_box.pack_start(&add_entry, true, true, 0);
+    add_box.pack_end(&add_button, false, false, 0);
+    vbox.pack_start(&add_box, false, false, 0);
+
+    add_button.connect_clicked({
+        let lists_box = lists_box.clone();
+        let list_updater = list_updater.clone();
+
+        move |_| {
+            let location = add_entry.text().trim().to_string();
+            if location.is_empty() {
+                return;
+            }
+
+            if Settings::update(|settings| settings.subscribe(&location, &location)) {
+                list_updater.update();
+                fill_filter_lists(&lists_box, &list_updater);
+            }
+            add_entry.set_text("");
+        }
+    });
+
+    let user_filters_label = Label::new(Some("My filters"));
+    user_filters_label.set_halign(gtk::Align::Start);
+    vbox.pack_start(&user_filters_label, false, false, 0);
+
+    let user_filters_view = TextView::new();
+    user_filters_view.set_monospace(true);
+    if let Some(buffer) = user_filters_view.buffer() {
+        buffer.set_text(&settings.user_filters);
+    }
+
+    let user_filters_scroll = ScrolledWindow::new(gtk::Adjustment::NONE, gtk::Adjustment::NONE);
+    user_filters_scroll.set_min_content_height(150);
+    user_filters_scroll.add(&user_filters_view);
+    vbox.pack_start(&user_filters_scroll, false, false, 0);
+
+    let apply_button = Button::with_label("Apply filters");
+    vbox.pack_start(&apply_button, false, false, 0);
+
+    apply_button.connect_clicked({
+        let list_updater = list_updater.clone();
+
+        move |_| {
+            if let Some(buffer) = user_filters_view.buffer() {
+                let (start, end) = buffer.bounds();
+                let text = buffer.text(&start, &end, false).unwrap_or_default();
+
+                Settings::update(|settings| settings.user_filters = text.to_string());
+                list_updater.update();
+            }
+        }
+    });
 
     let window_clone = window.clone();
     close_button.connect_clicked(move |_| window_clone.close());
 
-    window.add(&vbox);
+    let scrolled_window = ScrolledWindow::new(gtk::Adjustment::NONE, gtk::Adjustment::NONE);
+    scrolled_window.add(&vbox);
+
+    window.add(&scrolled_window);
     window.show_all();
 }
 // THIS HAS NO USAGE FOR NOW
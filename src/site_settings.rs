@@ -0,0 +1,182 @@
+//! The popover behind the "Site settings" icon of the URL bar.
+
+use crate::adblock_abrw;
+use crate::allowlist;
+use crate::bookmarks::Bookmarks;
+use crate::domain_list;
+use crate::log_window;
+use crate::opensearch;
+use crate::request_log;
+use crate::tabs::Browser;
+use crate::url_clean;
+use gtk::prelude::*;
+use url::Url;
+use webkit2gtk::{WebContext, WebViewExt};
+
+pub fn show_site_settings(browser: &Browser, search_entry: &gtk::Entry) {
+    let webview = match browser.current_webview() {
+        Some(webview) => webview,
+        None => return,
+    };
+
+    let popover = gtk::Popover::new(Some(search_entry));
+    popover.set_pointing_to(&search_entry.icon_area(gtk::EntryIconPosition::Secondary));
+
+    let vbox = gtk::Box::new(gtk::Orientation::Vertical, 10);
+    vbox.set_border_width(10);
+
+    let host = webview
+        .uri()
+        .and_then(|uri| Url::parse(&uri).ok())
+        .filter(|url| url.scheme() == "http" || url.scheme() == "https")
+        .and_then(|url| url.host_str().map(str::to_string));
+
+    let host = match host {
+        Some(host) => host,
+        None => {
+            vbox.pack_start(
+                &gtk::Label::new(Some("No site settings for this page")),
+                false,
+                false,
+                0,
+            );
+            popover.add(&vbox);
+            popover.show_all();
+            return;
+        }
+    };
+    let domain = domain_list::site_domain(&host).to_string();
+
+    let title = gtk::Label::new(None);
+    title.set_markup(&format!(
+        "<b>{}</b>",
+        gtk::glib::markup_escape_text(&domain)
+    ));
+    vbox.pack_start(&title, false, false, 0);
+
+    let bookmark_hbox = gtk::Box::new(gtk::Orientation::Horizontal, 10);
+    let bookmark_switch = gtk::Switch::new();
+    let page_url = webview.uri().map(|uri| uri.to_string()).unwrap_or_default();
+    bookmark_switch.set_active(Bookmarks::load().contains(&page_url));
+    bookmark_hbox.pack_start(&gtk::Label::new(Some("Bookmark")), false, false, 0);
+    bookmark_hbox.pack_end(&bookmark_switch, false, false, 0);
+    vbox.pack_start(&bookmark_hbox, false, false, 0);
+
+    let hbox = gtk::Box::new(gtk::Orientation::Horizontal, 10);
+    let switch = gtk::Switch::new();
+    switch.set_active(!allowlist::cached().contains(&host));
+    hbox.pack_start(&gtk::Label::new(Some("Block ads")), false, false, 0);
+    hbox.pack_end(&switch, false, false, 0);
+    vbox.pack_start(&hbox, false, false, 0);
+
+    let clean_hbox = gtk::Box::new(gtk::Orientation::Horizontal, 10);
+    let clean_switch = gtk::Switch::new();
+    clean_switch.set_active(!url_clean::exceptions().contains(&host));
+    clean_hbox.pack_start(
+        &gtk::Label::new(Some("Remove tracking parameters")),
+        false,
+        false,
+        0,
+    );
+    clean_hbox.pack_end(&clean_switch, false, false, 0);
+    vbox.pack_start(&clean_hbox, false, false, 0);
+
+    let blocked = browser.request_log.blocked(&webview);
+    let blocked_label = gtk::Label::new(Some(&request_log::blocked_text(blocked)));
+    blocked_label.set_tooltip_text(Some(request_log::CONTENT_BLOCKER_NOTE));
+    vbox.pack_start(&blocked_label, false, false, 0);
+
+    let log_button = gtk::Button::with_label("Show request log");
+    log_button.connect_clicked({
+        let request_log = browser.request_log.clone();
+        let content_filter = browser.content_filter.clone();
+        let webview = webview.clone();
+        let popover = popover.clone();
+
+        move |_| {
+            popover.popdown();
+            log_window::show_log_window(&request_log, &content_filter, &webview);
+        }
+    });
+    vbox.pack_start(&log_button, false, false, 0);
+
+    for link in browser.opensearch.links(&webview) {
+        let title = if link.title.is_empty() {
+            domain.clone()
+        } else {
+            link.title.clone()
+        };
+        let engine_button = gtk::Button::with_label(&format!("Add search engine \"{}\"", title));
+        if opensearch::is_added(&link) {
+            engine_button.set_label(&format!("Search engine \"{}\" added", title));
+            engine_button.set_sensitive(false);
+        }
+
+        engine_button.connect_clicked(move |button| {
+            button.set_sensitive(false);
+            button.set_label("Adding search engine…");
+
+            let button = button.clone();
+            opensearch::add_engine(&link, move |result| match result {
+                Ok(name) => button.set_label(&format!("Added \"{}\"", name)),
+                Err(err) => {
+                    button.set_label("Couldn't add search engine");
+                    button.set_tooltip_text(Some(&err));
+                    button.set_sensitive(true);
+                }
+            });
+        });
+        vbox.pack_start(&engine_button, false, false, 0);
+    }
+
+    bookmark_switch.connect_active_notify({
+        let webview = webview.clone();
+
+        move |switch| {
+            let mut bookmarks = Bookmarks::load();
+            if switch.is_active() {
+                let title = webview.title().map(|s| s.to_string()).unwrap_or_default();
+                bookmarks.add(&page_url, &title);
+            } else {
+                bookmarks.remove(&page_url);
+            }
+            bookmarks.save();
+        }
+    });
+
+    clean_switch.connect_active_notify({
+        let host = host.clone();
+        let domain = domain.clone();
+
+        move |switch| {
+            let mut exceptions = url_clean::exceptions();
+            if switch.is_active() {
+                exceptions.remove(&host);
+            } else {
+                exceptions.add(&domain);
+            }
+            exceptions.save();
+
+            // Only affects the next navigation, nothing to reload for.
+            adblock_abrw::notify_site_settings_updated(&WebContext::default().unwrap());
+        }
+    });
+
+    switch.connect_active_notify(move |switch| {
+        let mut allowlist = allowlist::load();
+        if switch.is_active() {
+            allowlist.remove(&host);
+        } else {
+            allowlist.add(&domain);
+        }
+        allowlist.save();
+
+        // The extensions reread the allowlist and the reload leaves the
+        // content filter off or puts it back, no rebuild needed.
+        adblock_abrw::notify_site_settings_updated(&WebContext::default().unwrap());
+        webview.reload();
+    });
+
+    popover.add(&vbox);
+    popover.show_all();
+}
@@ -0,0 +1,498 @@
+//! Suggestions in the URL bar's completion popup.
+//!
+//! Each [`SuggestionProvider`] offers scored rows for what is typed; the rows
+//! of all providers are merged, deduplicated and ranked by score. The
+//! calculator, history, bookmarks and open tabs are local and answer right
+//! away, so the popup is useful offline. The search engine's suggestions
+//! come from a single worker thread and are merged in when they arrive.
+//!
+//! Typing is debounced, and the worker skips to the newest query when
+//! several are waiting. Every query gets a generation number; replies for
+//! anything but the latest one are dropped, so a slow reply can't replace the
+//! suggestions for what is typed now.
+
+use crate::bookmarks::Bookmarks;
+use crate::calc;
+use crate::history::SharedHistory;
+use crate::omnibox;
+use crate::search::fetch_suggestions;
+use crate::tabs::Browser;
+use gtk::glib::{self, ControlFlow, MainContext, SourceId};
+use gtk::prelude::*;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use webkit2gtk::WebViewExt;
+
+/// How long typing has to pause before suggestions are fetched.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Rows shown in the popup at most.
+const MAX_ROWS: usize = 10;
+
+/// Rows a single local provider contributes at most.
+const MAX_LOCAL_ROWS: usize = 5;
+
+/// Columns of the popup's model.
+const KIND_COLUMN: u32 = 0;
+const TITLE_COLUMN: u32 = 1;
+const DETAIL_COLUMN: u32 = 2;
+const TEXT_COLUMN: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuggestionKind {
+    Calculator,
+    OpenTab,
+    Bookmark,
+    History,
+    Search,
+}
+
+impl SuggestionKind {
+    const ALL: [SuggestionKind; 5] = [
+        SuggestionKind::Calculator,
+        SuggestionKind::OpenTab,
+        SuggestionKind::Bookmark,
+        SuggestionKind::History,
+        SuggestionKind::Search,
+    ];
+
+    /// Shown in front of each row.
+    pub fn label(self) -> &'static str {
+        match self {
+            SuggestionKind::Calculator => "Copy",
+            SuggestionKind::OpenTab => "Switch to tab",
+            SuggestionKind::Bookmark => "Bookmark",
+            SuggestionKind::History => "History",
+            SuggestionKind::Search => "Search",
+        }
+    }
+
+    fn from_label(label: &str) -> Option<SuggestionKind> {
+        SuggestionKind::ALL
+            .into_iter()
+            .find(|kind| kind.label() == label)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Suggestion {
+    pub kind: SuggestionKind,
+    pub title: String,
+    /// What choosing the row opens: a URL, or a query for searches.
+    pub text: String,
+    /// Rows with higher scores come first.
+    pub score: f64,
+}
+
+pub trait SuggestionProvider {
+    fn suggest(&self, query: &str) -> Vec<Suggestion>;
+}
+
+/// How well `query` matches a page, or `None` if it doesn't: the start of
+/// the host or of a word in the title counts more than anywhere else.
+fn match_score(query: &str, url: &str, title: &str) -> Option<f64> {
+    let query = query.trim().to_lowercase();
+    let url = url.to_lowercase();
+    let title = title.to_lowercase();
+
+    let host = url::Url::parse(&url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let host = host.strip_prefix("www.").unwrap_or(&host);
+
+    if host.starts_with(&query) || url.starts_with(&query) {
+        Some(100.0)
+    } else if title
+        .split_whitespace()
+        .any(|word| word.starts_with(&query))
+    {
+        Some(50.0)
+    } else if url.contains(&query) || title.contains(&query) {
+        Some(0.0)
+    } else {
+        None
+    }
+}
+
+fn best_rows(mut rows: Vec<Suggestion>) -> Vec<Suggestion> {
+    rows.sort_by(|a, b| b.score.total_cmp(&a.score));
+    rows.truncate(MAX_LOCAL_ROWS);
+    rows
+}
+
+pub struct HistoryProvider {
+    pub history: SharedHistory,
+}
+
+impl SuggestionProvider for HistoryProvider {
+    fn suggest(&self, query: &str) -> Vec<Suggestion> {
+        let history = self.history.borrow();
+        let rows = history
+            .entries()
+            .iter()
+            .filter_map(|entry| {
+                let score = match_score(query, &entry.url, &entry.title)?;
+                Some(Suggestion {
+                    kind: SuggestionKind::History,
+                    title: entry.title.clone(),
+                    text: entry.url.clone(),
+                    // Often visited pages rank higher, up to a point.
+                    score: 100.0 + score + f64::from(entry.visits.min(50)) * 2.0,
+                })
+            })
+            .collect();
+
+        best_rows(rows)
+    }
+}
+
+pub struct BookmarkProvider;
+
+impl SuggestionProvider for BookmarkProvider {
+    fn suggest(&self, query: &str) -> Vec<Suggestion> {
+        let rows = Bookmarks::load()
+            .iter()
+            .filter_map(|bookmark| {
+                let score = match_score(query, &bookmark.url, &bookmark.title)?;
+                Some(Suggestion {
+                    kind: SuggestionKind::Bookmark,
+                    title: bookmark.title.clone(),
+                    text: bookmark.url.clone(),
+                    score: 200.0 + score,
+                })
+            })
+            .collect();
+
+        best_rows(rows)
+    }
+}
+
+/// Tabs other than the current one.
+pub struct OpenTabProvider {
+    pub notebook: gtk::Notebook,
+}
+
+impl SuggestionProvider for OpenTabProvider {
+    fn suggest(&self, query: &str) -> Vec<Suggestion> {
+        let current = self.notebook.current_page();
+        let rows = self
+            .notebook
+            .children()
+            .into_iter()
+            .filter(|widget| self.notebook.page_num(widget) != current)
+            .filter_map(|widget| widget.downcast::<webkit2gtk::WebView>().ok())
+            .filter_map(|webview| {
+                let url = webview.uri()?.to_string();
+                let title = webview.title().map(|s| s.to_string()).unwrap_or_default();
+                let score = match_score(query, &url, &title)?;
+                Some(Suggestion {
+                    kind: SuggestionKind::OpenTab,
+                    title,
+                    text: url,
+                    score: 300.0 + score,
+                })
+            })
+            .collect();
+
+        best_rows(rows)
+    }
+}
+
+/// The answer to a calculation or unit conversion, always the first row.
+pub struct CalculatorProvider;
+
+impl SuggestionProvider for CalculatorProvider {
+    fn suggest(&self, query: &str) -> Vec<Suggestion> {
+        calc::evaluate(query)
+            .map(|answer| Suggestion {
+                kind: SuggestionKind::Calculator,
+                title: format!("= {}", answer.display),
+                text: answer.value,
+                score: f64::INFINITY,
+            })
+            .into_iter()
+            .collect()
+    }
+}
+
+/// The search engine's suggestions for the query.
+pub struct RemoteProvider;
+
+impl SuggestionProvider for RemoteProvider {
+    fn suggest(&self, query: &str) -> Vec<Suggestion> {
+        // Addresses and calculations aren't sent to the search engine.
+        if omnibox::classify(query).is_url() || calc::evaluate(query).is_some() {
+            return Vec::new();
+        }
+
+        match fetch_suggestions(query) {
+            Ok(phrases) => phrases
+                .into_iter()
+                .enumerate()
+                .map(|(index, phrase)| Suggestion {
+                    kind: SuggestionKind::Search,
+                    title: phrase.clone(),
+                    text: phrase,
+                    // Keeps the engine's order.
+                    score: 50.0 - index as f64,
+                })
+                .collect(),
+            Err(err) => {
+                eprintln!("Failed to fetch suggestions: {}", err);
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// Sorts `rows` by score, keeping the best row for each URL or query.
+fn rank(mut rows: Vec<Suggestion>) -> Vec<Suggestion> {
+    rows.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    let mut seen = HashSet::new();
+    rows.retain(|row| seen.insert(row.text.clone()));
+    rows.truncate(MAX_ROWS);
+    rows
+}
+
+type Reply = (u64, Vec<Suggestion>);
+
+/// The thread asking a remote provider, fed with (generation, query).
+#[derive(Clone)]
+struct Worker(mpsc::Sender<(u64, String)>);
+
+impl Worker {
+    fn spawn<P>(provider: P, on_reply: glib::Sender<Reply>) -> Self
+    where
+        P: SuggestionProvider + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel::<(u64, String)>();
+
+        thread::spawn(move || {
+            while let Ok(mut request) = receiver.recv() {
+                // Only the newest of the queued queries is still wanted.
+                while let Ok(newer) = receiver.try_recv() {
+                    request = newer;
+                }
+
+                let (generation, text) = request;
+                if on_reply
+                    .send((generation, provider.suggest(&text)))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        Worker(sender)
+    }
+
+    fn fetch(&self, generation: u64, text: String) {
+        self.0.send((generation, text)).ok();
+    }
+}
+
+fn show(
+    completion: &gtk::EntryCompletion,
+    store: &gtk::ListStore,
+    search_entry: &gtk::Entry,
+    rows: &[Suggestion],
+) {
+    store.clear();
+    for row in rows {
+        // Search rows are their own title; pages show the URL below it.
+        let detail = match row.kind {
+            SuggestionKind::Calculator | SuggestionKind::Search => "",
+            _ => row.text.as_str(),
+        };
+        let title = if row.title.is_empty() {
+            &row.text
+        } else {
+            &row.title
+        };
+
+        store.insert_with_values(
+            None,
+            &[
+                (KIND_COLUMN, &row.kind.label()),
+                (TITLE_COLUMN, title),
+                (DETAIL_COLUMN, &detail),
+                (TEXT_COLUMN, &row.text),
+            ],
+        );
+    }
+
+    if search_entry.has_focus() {
+        // Refilters the model and shows or hides the popup.
+        completion.complete();
+    }
+}
+
+/// Switches to the tab showing `url`.
+fn switch_to_tab(notebook: &gtk::Notebook, url: &str) {
+    let page = notebook.children().into_iter().find(|widget| {
+        widget
+            .downcast_ref::<webkit2gtk::WebView>()
+            .and_then(|webview| webview.uri())
+            .as_deref()
+            == Some(url)
+    });
+
+    if let Some(page) = page {
+        notebook.set_current_page(notebook.page_num(&page));
+    }
+}
+
+fn add_column(completion: &gtk::EntryCompletion, column: u32, dim: bool) {
+    let renderer = gtk::CellRendererText::new();
+    renderer.set_ellipsize(gtk::pango::EllipsizeMode::End);
+    if dim {
+        renderer.set_foreground(Some("#8E9297"));
+    }
+    completion.pack_start(&renderer, !dim);
+    completion.add_attribute(&renderer, "text", column as i32);
+}
+
+/// Attaches the suggestion popup to the browser's URL bar.
+pub fn setup(browser: &Browser) {
+    let search_entry = &browser.search_entry;
+
+    let completion = gtk::EntryCompletion::new();
+    let store = gtk::ListStore::new(&[
+        glib::Type::STRING,
+        glib::Type::STRING,
+        glib::Type::STRING,
+        glib::Type::STRING,
+    ]);
+    completion.set_model(Some(&store));
+    add_column(&completion, KIND_COLUMN, true);
+    add_column(&completion, TITLE_COLUMN, false);
+    add_column(&completion, DETAIL_COLUMN, true);
+    // The providers already picked what matches; keyword searches and
+    // titles wouldn't pass the default prefix match anyway.
+    completion.set_match_func(|_, _, _| true);
+    completion.set_popup_single_match(true);
+    search_entry.set_completion(Some(&completion));
+
+    let local_providers: Vec<Box<dyn SuggestionProvider>> = vec![
+        Box::new(CalculatorProvider),
+        Box::new(OpenTabProvider {
+            notebook: browser.notebook.clone(),
+        }),
+        Box::new(BookmarkProvider),
+        Box::new(HistoryProvider {
+            history: browser.history.clone(),
+        }),
+    ];
+
+    let (sender, receiver) = MainContext::channel::<Reply>(glib::Priority::DEFAULT);
+    let worker = Worker::spawn(RemoteProvider, sender);
+    let generation = Rc::new(Cell::new(0u64));
+    let pending: Rc<RefCell<Option<SourceId>>> = Rc::new(RefCell::new(None));
+    // Rows of the local providers for the current generation, merged with
+    // the remote ones when they arrive.
+    let local_rows: Rc<RefCell<Vec<Suggestion>>> = Rc::new(RefCell::new(Vec::new()));
+
+    search_entry.connect_changed({
+        let completion = completion.clone();
+        let store = store.clone();
+        let generation = generation.clone();
+        let pending = pending.clone();
+        let local_rows = local_rows.clone();
+        let local_providers = Rc::new(local_providers);
+
+        move |entry| {
+            generation.set(generation.get() + 1);
+            if let Some(source) = pending.borrow_mut().take() {
+                source.remove();
+            }
+
+            // Text set by page loads isn't something the user is typing.
+            let text = entry.text().to_string();
+            if !entry.has_focus() || text.trim().is_empty() {
+                local_rows.borrow_mut().clear();
+                store.clear();
+                return;
+            }
+
+            let source = glib::timeout_add_local_once(DEBOUNCE, {
+                let completion = completion.clone();
+                let store = store.clone();
+                let entry = entry.clone();
+                let worker = worker.clone();
+                let pending = pending.clone();
+                let local_rows = local_rows.clone();
+                let local_providers = local_providers.clone();
+                let current = generation.get();
+
+                move || {
+                    pending.borrow_mut().take();
+
+                    let rows = local_providers
+                        .iter()
+                        .flat_map(|provider| provider.suggest(&text))
+                        .collect::<Vec<_>>();
+                    show(&completion, &store, &entry, &rank(rows.clone()));
+                    *local_rows.borrow_mut() = rows;
+
+                    worker.fetch(current, text);
+                }
+            });
+            *pending.borrow_mut() = Some(source);
+        }
+    });
+
+    // Replies still in flight when a search starts are of no use.
+    search_entry.connect_activate({
+        let generation = generation.clone();
+
+        move |_| {
+            generation.set(generation.get() + 1);
+            if let Some(source) = pending.borrow_mut().take() {
+                source.remove();
+            }
+        }
+    });
+
+    completion.connect_match_selected({
+        let notebook = browser.notebook.clone();
+        let search_entry = search_entry.clone();
+
+        move |_, model, iter| {
+            let kind = model.get::<String>(iter, KIND_COLUMN as i32);
+            let text = model.get::<String>(iter, TEXT_COLUMN as i32);
+
+            match SuggestionKind::from_label(&kind) {
+                Some(SuggestionKind::Calculator) => calc::copy(&text),
+                Some(SuggestionKind::OpenTab) => switch_to_tab(&notebook, &text),
+                _ => {
+                    search_entry.set_text(&text);
+                    search_entry.emit_activate();
+                }
+            }
+            glib::Propagation::Stop
+        }
+    });
+
+    receiver.attach(None, {
+        let search_entry = search_entry.clone();
+
+        move |(reply_generation, remote_rows)| {
+            if reply_generation != generation.get() {
+                return ControlFlow::Continue;
+            }
+
+            let mut rows = local_rows.borrow().clone();
+            rows.extend(remote_rows);
+            show(&completion, &store, &search_entry, &rank(rows));
+
+            ControlFlow::Continue
+        }
+    });
+}
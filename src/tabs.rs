@@ -1,46 +1,154 @@
 extern crate gtk;
 extern crate webkit2gtk;
 
-use crate::{adblock_abrw, settings};
-use adblock::{lists::FilterSet, Engine};
+use crate::{
+    adblock_abrw::{ListUpdater, SharedEngine},
+    closed_tabs::{ClosedTab, ClosedTabs},
+    content_filter::ContentFilter,
+    cosmetic,
+    history::{self, SharedHistory},
+    https_only,
+    opensearch::Discovered,
+    request_log::RequestLog,
+    settings,
+};
 use gtk::{
+    gdk,
     gdk_pixbuf::{InterpType, Pixbuf},
     prelude::*,
 };
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use webkit2gtk::{SettingsExt, WebViewExt};
-
-pub fn add_webview_tab(
-    notebook: &gtk::Notebook,
-    _url: &str,
-    title: &str,
-    search_entry: &gtk::Entry,
-    filter_set: &Arc<Mutex<FilterSet>>,
-) {
-    let webview = webkit2gtk::WebView::new();
-    let engine = Engine::from_filter_set(filter_set.lock().unwrap().clone(), true);
-
-    webview.connect_resource_load_started(move |webview, resource, request| {
-        adblock_abrw::on_resource_load_started(webview, resource, request, &engine);
-    });
+use std::rc::Rc;
+use url::Url;
+use webkit2gtk::{
+    BackForwardListExt, NavigationAction, NavigationPolicyDecision, NavigationPolicyDecisionExt,
+    NavigationType, PolicyDecisionExt, PolicyDecisionType, SettingsExt, URIRequestExt, WebView,
+    WebViewExt,
+};
 
-    let web_view_settings = WebViewExt::settings(&webview).unwrap();
-    let web_view_settings_json = settings::Settings::load();
+/// The parts of the browser window every tab needs.
+#[derive(Clone)]
+pub struct Browser {
+    pub notebook: gtk::Notebook,
+    pub search_entry: gtk::Entry,
+    pub engine: SharedEngine,
+    pub content_filter: ContentFilter,
+    pub list_updater: ListUpdater,
+    pub request_log: RequestLog,
+    /// OpenSearch descriptions the page in each tab links to.
+    pub opensearch: Discovered,
+    /// The tab each tab was opened from, while both are open.
+    pub openers: Rc<RefCell<HashMap<WebView, WebView>>>,
+    /// Tabs closed in this session, for reopening.
+    pub closed_tabs: ClosedTabs,
+    pub history: SharedHistory,
+}
 
-    // SETTINGS
-    web_view_settings.set_enable_developer_extras(true);
-    web_view_settings.set_enable_smooth_scrolling(true);
+impl Browser {
+    pub fn current_webview(&self) -> Option<webkit2gtk::WebView> {
+        self.notebook
+            .nth_page(self.notebook.current_page())
+            .and_then(|widget| widget.downcast::<webkit2gtk::WebView>().ok())
+    }
+}
 
-    web_view_settings.set_enable_javascript(web_view_settings_json.enable_javascript);
-    web_view_settings.set_enable_webgl(web_view_settings_json.enable_webgl);
-    web_view_settings.set_enable_page_cache(web_view_settings_json.page_cache);
-    web_view_settings.set_media_playback_requires_user_gesture(
-        web_view_settings_json.media_playback_requires_user_gesture,
+/// How `add_webview_tab` opens a tab.
+#[derive(Default)]
+pub struct TabOptions {
+    /// The page to load. `None` shows the new tab page.
+    pub url: Option<String>,
+    /// Opens the tab without switching to it.
+    pub background: bool,
+    /// The tab this one is opened from, such as by a link. The new tab
+    /// shares its web process and goes right after it and the tabs opened
+    /// from it before; without an opener tabs go at the end.
+    pub opener: Option<WebView>,
+    /// Where to put the tab, instead of by its opener.
+    pub position: Option<u32>,
+}
+
+/// Whether `tab` was opened from `opener`, or from a tab opened from it.
+fn descends_from(openers: &HashMap<WebView, WebView>, tab: &WebView, opener: &WebView) -> bool {
+    let mut tab = tab;
+    while let Some(parent) = openers.get(tab) {
+        if parent == opener {
+            return true;
+        }
+        tab = parent;
+    }
+    false
+}
+
+/// The position for a tab opened from `opener`, past the tabs related to it.
+fn position_after(browser: &Browser, opener: &WebView) -> Option<u32> {
+    let notebook = &browser.notebook;
+    let openers = browser.openers.borrow();
+
+    let mut position = notebook.page_num(opener)? + 1;
+    while let Some(page) = notebook.nth_page(Some(position)) {
+        match page.downcast_ref::<WebView>() {
+            Some(tab) if descends_from(&openers, tab, opener) => position += 1,
+            _ => break,
+        }
+    }
+    Some(position)
+}
+
+/// Handles `abp:subscribe?location=...&title=...` links by asking whether to
+/// add the list. Returns `true` if `uri` was such a link.
+fn handle_subscribe_link(
+    webview: &webkit2gtk::WebView,
+    uri: &str,
+    list_updater: &ListUpdater,
+) -> bool {
+    let url = match Url::parse(uri) {
+        Ok(url) if url.scheme() == "abp" && url.path() == "subscribe" => url,
+        _ => return false,
+    };
+
+    let query = |key: &str| {
+        url.query_pairs()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.to_string())
+    };
+
+    let location = match query("location") {
+        Some(location) => location,
+        None => return true,
+    };
+    let title = query("title").unwrap_or_else(|| location.clone());
+
+    let dialog = gtk::MessageDialog::new(
+        webview
+            .toplevel()
+            .and_then(|w| w.downcast::<gtk::Window>().ok())
+            .as_ref(),
+        gtk::DialogFlags::MODAL,
+        gtk::MessageType::Question,
+        gtk::ButtonsType::YesNo,
+        &format!("Subscribe to the filter list \"{}\"?", title),
     );
-    web_view_settings.set_user_agent(Some("aapelix/abrw"));
+    dialog.set_secondary_text(Some(&location));
+
+    let list_updater = list_updater.clone();
+    dialog.connect_response(move |dialog, response| {
+        if response == gtk::ResponseType::Yes {
+            let mut settings = settings::Settings::load();
+            if settings.subscribe(&title, &location) {
+                settings.save();
+                list_updater.update();
+            }
+        }
+        dialog.close();
+    });
+    dialog.show();
+
+    true
+}
 
-    let html_data = r#"<!DOCTYPE html>
+const NEW_TAB_HTML: &str = r#"<!DOCTYPE html>
     <html lang="en">
     <head>
         <meta charset="UTF-8">
@@ -89,39 +197,138 @@ pub fn add_webview_tab(
     </html>
 "#;
 
-    webview.load_html(&html_data, None);
+/// Middle-click and Ctrl+click on a link open it in a background tab.
+fn opens_in_background(action: &NavigationAction) -> bool {
+    let control = action.modifiers() & gdk::ModifierType::CONTROL_MASK.bits() != 0;
+    action.navigation_type() == NavigationType::LinkClicked
+        && (action.mouse_button() == 2 || (action.mouse_button() == 1 && control))
+}
 
-    let search_entry_clone = search_entry.clone();
-    webview.connect_notify_local(Some("uri"), move |webview, _| {
-        if let Some(uri) = webview.uri() {
-            search_entry_clone.set_text(&uri);
+pub fn add_webview_tab(browser: &Browser, options: TabOptions) -> WebView {
+    let webview = open_tab(browser, &options);
+    match &options.url {
+        Some(url) => webview.load_uri(url),
+        None => webview.load_html(NEW_TAB_HTML, None),
+    }
+    webview
+}
+
+/// Adds a tab with nothing loaded in it.
+fn open_tab(browser: &Browser, options: &TabOptions) -> WebView {
+    let notebook = &browser.notebook;
+    let search_entry = &browser.search_entry;
+
+    // Related views share a web process, which `window.open` needs to hand
+    // the page its opener. The settings and user content stay per tab.
+    let webview = match &options.opener {
+        Some(opener) => WebView::builder().related_view(opener).build(),
+        None => WebView::new(),
+    };
+    browser.content_filter.attach(&webview);
+
+    let browser_clone = browser.clone();
+    webview.connect_decide_policy(move |webview, decision, decision_type| {
+        if decision_type != PolicyDecisionType::NavigationAction
+            && decision_type != PolicyDecisionType::NewWindowAction
+        {
+            return false;
+        }
+
+        let action = decision
+            .downcast_ref::<NavigationPolicyDecision>()
+            .and_then(|decision| decision.navigation_action());
+        let uri = action
+            .as_ref()
+            .and_then(|action| action.request())
+            .and_then(|request| request.uri());
+
+        if let (Some(action), Some(uri)) = (&action, &uri) {
+            if opens_in_background(action) {
+                decision.ignore();
+                add_webview_tab(
+                    &browser_clone,
+                    TabOptions {
+                        url: Some(uri.to_string()),
+                        background: true,
+                        opener: Some(webview.clone()),
+                        ..Default::default()
+                    },
+                );
+                return true;
+            }
+        }
+
+        // Other new windows are opened by the create handler.
+        if decision_type == PolicyDecisionType::NewWindowAction {
+            return false;
+        }
+
+        match uri {
+            Some(uri) if handle_subscribe_link(webview, &uri, &browser_clone.list_updater) => {
+                decision.ignore();
+                true
+            }
+            _ => false,
         }
     });
 
-    let notebook_clone = notebook.clone();
-    webview.connect_title_notify(move |webview| {
-        let notebook = notebook_clone.clone();
-        let webview = webview.clone();
-
-        let title = webview
-            .title()
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| "Untitled".to_string());
-
-        println!("Title changed {}", title);
-
-        let current_page = notebook.current_page();
-
-        if let Some(page) = notebook.nth_page(current_page) {
-            if let Some(tab) = notebook.tab_label(&page) {
-                if let Some(tab_box) = tab.downcast_ref::<gtk::Container>() {
-                    for child in tab_box.children() {
-                        if let Some(label) = child.downcast_ref::<gtk::Label>() {
-                            label.set_label(&title);
-                        }
-                    }
-                }
+    // `window.open` and `target=_blank` links. WebKit loads the page into
+    // the view returned, and gives it `window.opener` as it's related.
+    webview.connect_create({
+        let browser = browser.clone();
+
+        move |webview, action| {
+            if !action.is_user_gesture() && !settings::Settings::load().allow_popups {
+                println!(
+                    "Blocked a pop-up from {}",
+                    webview.uri().unwrap_or_default()
+                );
+                return None;
             }
+
+            let tab = open_tab(
+                &browser,
+                &TabOptions {
+                    opener: Some(webview.clone()),
+                    ..Default::default()
+                },
+            );
+            Some(tab.upcast())
+        }
+    });
+
+    https_only::setup(&webview);
+    history::setup(&webview, &browser.history);
+    browser.opensearch.watch(&webview);
+
+    cosmetic::setup(&webview, &browser.engine);
+
+    let web_view_settings = WebViewExt::settings(&webview).unwrap();
+    let web_view_settings_json = settings::Settings::load();
+
+    // SETTINGS
+    web_view_settings.set_enable_developer_extras(true);
+    web_view_settings.set_enable_smooth_scrolling(true);
+
+    web_view_settings.set_enable_javascript(web_view_settings_json.enable_javascript);
+    web_view_settings.set_enable_webgl(web_view_settings_json.enable_webgl);
+    web_view_settings.set_enable_page_cache(web_view_settings_json.page_cache);
+    web_view_settings
+        .set_javascript_can_open_windows_automatically(web_view_settings_json.allow_popups);
+    web_view_settings.set_media_playback_requires_user_gesture(
+        web_view_settings_json.media_playback_requires_user_gesture,
+    );
+    web_view_settings.set_user_agent(Some("aapelix/abrw"));
+
+    // Background tabs leave the URL bar to the tab being shown.
+    let search_entry_clone = search_entry.clone();
+    let notebook_clone = notebook.clone();
+    webview.connect_notify_local(Some("uri"), move |webview, _| {
+        if notebook_clone.page_num(webview) != notebook_clone.current_page() {
+            return;
+        }
+        if let Some(uri) = webview.uri() {
+            search_entry_clone.set_text(&uri);
         }
     });
 
@@ -165,7 +372,22 @@ pub fn add_webview_tab(
     let scaled_pixbuf = &pixbuf_icon.scale_simple(25, 25, InterpType::Bilinear);
 
     let icon = gtk::Image::from_pixbuf(scaled_pixbuf.as_ref());
-    let label = gtk::Label::new(Some(title));
+    let label = gtk::Label::new(Some("New tab"));
+
+    webview.connect_title_notify({
+        let label = label.clone();
+
+        move |webview| {
+            let title = webview
+                .title()
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| "Untitled".to_string());
+
+            println!("Title changed {}", title);
+
+            label.set_label(&title);
+        }
+    });
 
     icon.set_pixel_size(2000);
 
@@ -173,8 +395,15 @@ pub fn add_webview_tab(
     // label.set_hexpand(true);
     label.set_vexpand(false);
 
+    // Number of requests the request filter blocked on the page, hidden while
+    // there are none.
+    let badge = gtk::Label::new(None);
+    badge.style_context().add_class("badge");
+    browser.request_log.watch(&webview, &badge);
+
     hbox.pack_start(&icon, false, false, 10);
     hbox.pack_start(&label, false, false, 10);
+    hbox.pack_start(&badge, false, false, 0);
 
     let close_button = gtk::Button::new();
 
@@ -197,16 +426,40 @@ pub fn add_webview_tab(
         button:hover {
             background: #313131;
         }
+
+        .badge {
+            background: #5865F2;
+            color: #FFFFFF;
+            border-radius: 7px;
+            padding: 0 5px;
+            font-size: smaller;
+        }
     ",
         )
         .expect("Failed to load css");
 
     let style_context = close_button.style_context();
     style_context.add_provider(&css_provider, gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
+    badge
+        .style_context()
+        .add_provider(&css_provider, gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
 
     hbox.pack_start(&close_button, false, false, 0);
 
-    let new_tab_index = notebook.append_page(&webview, Some(&hbox));
+    let position = options.position.or_else(|| {
+        options
+            .opener
+            .as_ref()
+            .and_then(|opener| position_after(browser, opener))
+    });
+    let new_tab_index = notebook.insert_page(&webview, Some(&hbox), position);
+
+    if let Some(opener) = &options.opener {
+        browser
+            .openers
+            .borrow_mut()
+            .insert(webview.clone(), opener.clone());
+    }
 
     notebook.set_tab_reorderable(&webview, true);
     notebook.set_tab_detachable(&webview, true);
@@ -218,12 +471,85 @@ pub fn add_webview_tab(
     close_button.show();
     hbox.show();
 
-    notebook.set_current_page(Some(new_tab_index));
-
-    let notebook_clone = notebook.clone();
+    let browser_clone = browser.clone();
+    let webview_weak = webview.downgrade();
     close_button.connect_clicked(move |_| {
-        notebook_clone.remove_page(Some(new_tab_index));
+        if let Some(webview) = webview_weak.upgrade() {
+            close_tab(&browser_clone, &webview);
+        }
     });
 
-    search_entry.set_is_focus(true);
+    // `window.close()`, which WebKit allows for windows a script opened.
+    let browser_clone = browser.clone();
+    webview.connect_close(move |webview| close_tab(&browser_clone, webview));
+
+    if !options.background {
+        notebook.set_current_page(Some(new_tab_index));
+
+        // Typing goes to the URL bar in a tab the user asked for.
+        if options.opener.is_none() {
+            search_entry.set_is_focus(true);
+        }
+    }
+
+    webview
+}
+
+/// Closes the tab showing `webview`, wherever it has moved to, and keeps it
+/// for `reopen_closed_tab`.
+pub fn close_tab(browser: &Browser, webview: &WebView) {
+    let position = match browser.notebook.page_num(webview) {
+        Some(position) => position,
+        None => return,
+    };
+
+    // Nothing to bring back from a new tab page.
+    if let Some(url) = webview.uri().filter(|url| url != "about:blank") {
+        browser.closed_tabs.push(ClosedTab {
+            url: url.to_string(),
+            title: webview
+                .title()
+                .map(|title| title.to_string())
+                .unwrap_or_else(|| url.to_string()),
+            session: webview.session_state(),
+            position,
+        });
+    }
+
+    browser.request_log.forget(webview);
+    browser.opensearch.forget(webview);
+    {
+        let mut openers = browser.openers.borrow_mut();
+        openers.remove(webview);
+        openers.retain(|_, opener| opener != webview);
+    }
+
+    browser.notebook.remove_page(Some(position));
+}
+
+/// Reopens the closed tab at `index`, most recent first, where it was and
+/// with its back/forward list.
+pub fn reopen_closed_tab(browser: &Browser, index: usize) {
+    let closed = match browser.closed_tabs.take(index) {
+        Some(closed) => closed,
+        None => return,
+    };
+
+    let webview = open_tab(
+        browser,
+        &TabOptions {
+            position: Some(closed.position),
+            ..Default::default()
+        },
+    );
+    webview.restore_session_state(&closed.session);
+
+    // Restoring the list doesn't load its current item.
+    match webview
+        .back_forward_list()
+        .and_then(|list| list.current_item())
+    {
+        Some(item) => webview.go_to_back_forward_list_item(&item),
+        None => webview.load_uri(&closed.url),
+    }
 }
@@ -0,0 +1,75 @@
+//! Removing tracking parameters (`utm_*`, `fbclid`, ...) from URLs.
+//!
+//! The built-in parameters are turned into `$removeparam` rules and join the
+//! ones from the filter lists in the engine, so there is one set of rules for
+//! both places URLs get cleaned: here for addresses typed into the URL bar,
+//! and in the web process extension for link clicks, redirects and frames.
+//! Sites can opt out, for when a parameter turns out to be needed.
+
+use crate::adblock_abrw::{self, SharedEngine};
+use crate::domain_list::{DomainList, Format};
+use adblock::request::Request;
+use std::path::PathBuf;
+
+/// Parameters stripped even when no subscribed list removes them.
+const TRACKING_PARAMS: &[&str] = &[
+    "utm_source",
+    "utm_medium",
+    "utm_campaign",
+    "utm_term",
+    "utm_content",
+    "utm_id",
+    "utm_name",
+    "utm_reader",
+    "utm_social",
+    "utm_social-type",
+    "fbclid",
+    "gclid",
+    "gclsrc",
+    "dclid",
+    "gbraid",
+    "wbraid",
+    "msclkid",
+    "yclid",
+    "twclid",
+    "ttclid",
+    "igshid",
+    "mc_cid",
+    "mc_eid",
+    "_hsenc",
+    "_hsmi",
+    "mkt_tok",
+    "oly_anon_id",
+    "oly_enc_id",
+    "vero_id",
+    "wickedid",
+];
+
+/// The built-in parameters as filter rules.
+pub fn builtin_rules() -> Vec<String> {
+    TRACKING_PARAMS
+        .iter()
+        .map(|param| format!("*$removeparam={}", param))
+        .collect()
+}
+
+pub fn exceptions_path() -> PathBuf {
+    adblock_abrw::cache_dir().join("url-clean-exceptions.txt")
+}
+
+/// Sites whose URLs are left as they are.
+pub fn exceptions() -> DomainList {
+    DomainList::load(exceptions_path(), Format::Domains)
+}
+
+/// `url` without its tracking parameters, or `None` if there was nothing to
+/// remove or the site opted out.
+pub fn clean_url(engine: &SharedEngine, url: &url::Url) -> Option<String> {
+    let host = url.host_str()?;
+    if exceptions().contains(host) {
+        return None;
+    }
+
+    let request = Request::new(url.as_str(), url.as_str(), "document").ok()?;
+    engine.get().check_network_request(&request).rewritten_url
+}
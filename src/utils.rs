@@ -1,6 +1,11 @@
-use regex::Regex;
+use std::fs;
+use std::path::PathBuf;
 
-pub fn is_url(text: &str) -> bool {
-    let url_regex = Regex::new(r"^(https?://[^\s/$.?#].[^\s]*)$").unwrap();
-    url_regex.is_match(text)
+/// Per-user data directory (`~/.local/share/abrw`), created on first use.
+pub fn data_dir() -> PathBuf {
+    let dir = gtk::glib::user_data_dir().join("abrw");
+    if let Err(err) = fs::create_dir_all(&dir) {
+        eprintln!("Failed to create {}: {}", dir.display(), err);
+    }
+    dir
 }
@@ -0,0 +1,489 @@
+//! Web process side of the ad blocker.
+//!
+//! WebKit loads this library into every web process. It hooks the page's
+//! `send-request` signal, which fires for each subresource before it goes out,
+//! so a matching request can be cancelled on its own while the rest of the
+//! page keeps loading. Requests matching a `$redirect=` rule are pointed at
+//! the neutered local resource instead.
+//!
+//! Pages on the user's allowlist are left alone. Blocked and redirected
+//! requests are reported to the view, which keeps the request log and badge
+//! of the tab; allowed ones only while a log window asks for them.
+//!
+//! Documents and frames loaded from a URL with tracking parameters are
+//! requested without them, following the engine's `$removeparam` rules.
+//!
+//! In HTTPS-only mode `http://` requests are upgraded before anything else.
+
+mod request_type;
+
+use adblock::{request::Request, resources::Resource, Engine};
+use glib::prelude::*;
+use glib::translate::*;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::ffi::{c_char, c_void, CStr};
+use std::fs;
+use std::rc::Rc;
+use url::{Host, Url};
+
+/// The request headers aren't exposed as a property and there are no
+/// bindings for messages to the view, so these go through the C API directly.
+mod ffi {
+    use super::*;
+
+    #[link(name = "webkit2gtk-4.1")]
+    extern "C" {
+        pub fn webkit_uri_request_get_http_headers(
+            request: *mut glib::gobject_ffi::GObject,
+        ) -> *mut c_void;
+
+        pub fn webkit_user_message_new(
+            name: *const c_char,
+            parameters: *mut glib::ffi::GVariant,
+        ) -> *mut glib::gobject_ffi::GObject;
+
+        pub fn webkit_web_page_send_message_to_view(
+            page: *mut glib::gobject_ffi::GObject,
+            message: *mut glib::gobject_ffi::GObject,
+            cancellable: *mut c_void,
+            callback: *mut c_void,
+            user_data: *mut c_void,
+        );
+    }
+
+    #[link(name = "soup-3.0")]
+    extern "C" {
+        pub fn soup_message_headers_get_one(
+            headers: *mut c_void,
+            name: *const c_char,
+        ) -> *const c_char;
+    }
+}
+
+/// The value of the request header `name`, which must end in a NUL.
+fn header(request: &glib::Object, name: &[u8]) -> Option<String> {
+    unsafe {
+        let headers = ffi::webkit_uri_request_get_http_headers(request.as_ptr());
+        if headers.is_null() {
+            return None;
+        }
+
+        let value = ffi::soup_message_headers_get_one(headers, name.as_ptr() as *const c_char);
+        if value.is_null() {
+            return None;
+        }
+
+        Some(CStr::from_ptr(value).to_string_lossy().into_owned())
+    }
+}
+
+/// Sent by the browser after it wrote a new engine to disk.
+const ENGINE_UPDATED_MESSAGE: &str = "abrw-engine-updated";
+
+/// Sent by the browser when a per-site setting that isn't part of the engine
+/// changed. The parameter says whether HTTPS-only mode is on.
+const SITE_SETTINGS_UPDATED_MESSAGE: &str = "abrw-site-settings-updated";
+
+/// Sent to the view with the original URL when a page load was upgraded to
+/// HTTPS, so a failure can be explained.
+const HTTPS_UPGRADED_MESSAGE: &str = "abrw-https-upgraded";
+
+/// Sent to the view for every checked request. The parameters are
+/// `(url, type, blocked, filter, exception, redirected)`, with empty strings
+/// for a missing filter or exception.
+const REQUEST_CHECKED_MESSAGE: &str = "abrw-request-checked";
+
+/// Sent by the view while a log window is open for the page, with whether
+/// allowed requests should be reported too. Otherwise only blocked and
+/// redirected requests are, so most requests don't cost a message.
+const LOG_ALLOWED_MESSAGE: &str = "abrw-log-allowed";
+
+/// Exception reported for requests made by a page on the allowlist.
+const ALLOWLIST_EXCEPTION: &str = "site allowlist";
+
+fn send_to_view(page: &glib::Object, name: &str, parameters: &glib::Variant) {
+    unsafe {
+        // The message is floating, sending it takes ownership.
+        let message =
+            ffi::webkit_user_message_new(name.to_glib_none().0, parameters.to_glib_none().0);
+        ffi::webkit_web_page_send_message_to_view(
+            page.as_ptr(),
+            message,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        );
+    }
+}
+
+/// The initialization user data: files written by the browser and whether
+/// HTTPS-only mode is on.
+#[derive(Default, glib::Variant)]
+struct InitData {
+    engine: String,
+    resources: String,
+    allowlist: String,
+    clean_exceptions: String,
+    https_exceptions: String,
+    https_only: bool,
+}
+
+fn contains_domain(domains: &HashSet<String>, host: &str) -> bool {
+    let mut host = host;
+    loop {
+        if domains.contains(host) {
+            return true;
+        }
+        match host.split_once('.') {
+            Some((_, parent)) => host = parent,
+            None => return false,
+        }
+    }
+}
+
+/// Per-site settings kept outside the engine.
+struct Sites {
+    allowlist: HashSet<String>,
+    clean_exceptions: HashSet<String>,
+    https_exceptions: HashSet<String>,
+}
+
+impl Sites {
+    fn load(data: &InitData) -> Sites {
+        Sites {
+            allowlist: load_allowlist(&data.allowlist),
+            clean_exceptions: load_domains(&data.clean_exceptions),
+            https_exceptions: load_domains(&data.https_exceptions),
+        }
+    }
+}
+
+struct Blocker {
+    engine: Engine,
+    sites: Sites,
+    https_only: bool,
+}
+
+impl Blocker {
+    fn load(data: &InitData) -> Blocker {
+        Blocker {
+            engine: load_engine(&data.engine, &data.resources),
+            sites: Sites::load(data),
+            https_only: data.https_only,
+        }
+    }
+
+    fn is_allowed(&self, host: &str) -> bool {
+        contains_domain(&self.sites.allowlist, host)
+    }
+
+    fn cleans_urls(&self, host: &str) -> bool {
+        !contains_domain(&self.sites.clean_exceptions, host)
+    }
+
+    /// The `https://` version of `url` if HTTPS-only mode applies to it.
+    fn https_upgrade(&self, url: &Url) -> Option<Url> {
+        if !self.https_only || url.scheme() != "http" || is_local(url) {
+            return None;
+        }
+        if contains_domain(&self.sites.https_exceptions, url.host_str()?) {
+            return None;
+        }
+
+        let mut upgraded = url.clone();
+        upgraded.set_scheme("https").ok()?;
+        if upgraded.port() == Some(80) {
+            upgraded.set_port(None).ok()?;
+        }
+        Some(upgraded)
+    }
+}
+
+/// Local hosts rarely have a certificate, so they are never upgraded. Public
+/// addresses are upgraded like any other host.
+fn is_local(url: &Url) -> bool {
+    match url.host() {
+        Some(Host::Domain(domain)) => {
+            domain == "localhost" || domain.ends_with(".localhost") || domain.ends_with(".local")
+        }
+        Some(Host::Ipv4(ip)) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        Some(Host::Ipv6(ip)) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                // Unique local, fc00::/7.
+                || first & 0xfe00 == 0xfc00
+                // Link-local, fe80::/10.
+                || first & 0xffc0 == 0xfe80
+        }
+        None => true,
+    }
+}
+
+/// Domains of the `@@||domain^$document` rules written by the browser.
+fn load_allowlist(path: &str) -> HashSet<String> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            line.trim()
+                .strip_prefix("@@||")?
+                .strip_suffix("^$document")
+                .map(str::to_string)
+        })
+        .collect()
+}
+
+/// A file with one domain per line.
+fn load_domains(path: &str) -> HashSet<String> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn load_engine(path: &str, resources_path: &str) -> Engine {
+    let mut engine = Engine::new(true);
+    if let Ok(data) = fs::read(path) {
+        if let Err(err) = engine.deserialize(&data) {
+            eprintln!("Failed to load adblock engine {}: {:?}", path, err);
+        }
+    }
+
+    let resources = match fs::read_to_string(resources_path) {
+        Ok(data) => serde_json::from_str::<Vec<Resource>>(&data).unwrap_or_else(|err| {
+            eprintln!(
+                "Failed to parse adblock resources {}: {}",
+                resources_path, err
+            );
+            Vec::new()
+        }),
+        Err(err) => {
+            eprintln!(
+                "WARNING: adblock resources not loaded from {}: {}",
+                resources_path, err
+            );
+            Vec::new()
+        }
+    };
+    engine.use_resources(resources);
+
+    engine
+}
+
+/// Returns `true` when the request should be cancelled. Allowed requests are
+/// only reported when `log_allowed` is set.
+fn on_send_request(
+    page: &glib::Object,
+    request: &glib::Object,
+    blocker: &Blocker,
+    log_allowed: bool,
+) -> bool {
+    let Some(mut uri) = request.property::<Option<String>>("uri") else {
+        return false;
+    };
+
+    let mut url = match Url::parse(&uri) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+
+    // Rules like `$third-party` and `$domain=` are relative to the page the
+    // request is made from, which is the request itself for a new document.
+    let page_uri = page
+        .property::<Option<String>>("uri")
+        .filter(|page_uri| page_uri.starts_with("http"));
+    let is_document = page_uri.is_none() || page_uri.as_deref() == Some(url.as_str());
+
+    if let Some(upgraded) = blocker.https_upgrade(&url) {
+        if is_document {
+            send_to_view(page, HTTPS_UPGRADED_MESSAGE, &uri.to_variant());
+        }
+        uri = upgraded.to_string();
+        request.set_property("uri", &uri);
+        url = upgraded;
+    }
+
+    let source_url = match page_uri {
+        Some(page_uri) if !is_document => page_uri,
+        _ => url.to_string(),
+    };
+
+    let request_type = request_type::request_type(
+        &url,
+        header(request, b"Sec-Fetch-Dest\0").as_deref(),
+        header(request, b"Accept\0").as_deref(),
+        is_document,
+    );
+
+    let allowed = Url::parse(&source_url)
+        .ok()
+        .and_then(|source| source.host_str().map(|host| blocker.is_allowed(host)))
+        .unwrap_or(false);
+    if allowed {
+        if !log_allowed {
+            return false;
+        }
+        let entry = (
+            uri,
+            request_type.to_string(),
+            false,
+            String::new(),
+            ALLOWLIST_EXCEPTION.to_string(),
+            false,
+        );
+        send_to_view(page, REQUEST_CHECKED_MESSAGE, &entry.to_variant());
+        return false;
+    }
+
+    match Request::new(url.as_str(), &source_url, request_type) {
+        Ok(req) => {
+            let result = blocker.engine.check_network_request(&req);
+            let blocked = result.matched && result.redirect.is_none();
+
+            if blocked || result.redirect.is_some() || log_allowed {
+                let entry = (
+                    uri,
+                    request_type.to_string(),
+                    blocked,
+                    result.filter.unwrap_or_default(),
+                    result.exception.unwrap_or_default(),
+                    result.redirect.is_some(),
+                );
+                send_to_view(page, REQUEST_CHECKED_MESSAGE, &entry.to_variant());
+            }
+
+            if let Some(redirect) = result.redirect {
+                // A data: URL holding the replacement resource.
+                request.set_property("uri", redirect);
+            } else if let Some(rewritten_url) = result.rewritten_url {
+                let is_frame = request_type == "document" || request_type == "subdocument";
+                let cleans_urls = url
+                    .host_str()
+                    .map_or(false, |host| blocker.cleans_urls(host));
+                if !blocked && is_frame && cleans_urls {
+                    request.set_property("uri", rewritten_url);
+                }
+            }
+            blocked
+        }
+        Err(err) => {
+            eprintln!("Error creating request: {}", err);
+            false
+        }
+    }
+}
+
+/// Entry point looked up by WebKit. `user_data` holds the `InitData` set with
+/// `set_web_extensions_initialization_user_data`.
+///
+/// # Safety
+///
+/// Called once by WebKit with a valid `WebKitWebExtension` and `GVariant`.
+#[no_mangle]
+pub unsafe extern "C" fn webkit_web_extension_initialize_with_user_data(
+    extension: *mut glib::gobject_ffi::GObject,
+    user_data: *const glib::ffi::GVariant,
+) {
+    let extension: glib::Object = from_glib_none(extension);
+    let user_data: glib::Variant = from_glib_none(user_data);
+
+    let data = user_data.get::<InitData>().unwrap_or_default();
+    let blocker = Rc::new(RefCell::new(Blocker::load(&data)));
+
+    extension.connect_local("user-message-received", false, {
+        let blocker = blocker.clone();
+
+        move |values| {
+            let message = values[1].get::<glib::Object>().ok()?;
+            let name = message.property::<Option<String>>("name");
+            let mut blocker = blocker.borrow_mut();
+            match name.as_deref() {
+                Some(ENGINE_UPDATED_MESSAGE) => {
+                    blocker.engine = load_engine(&data.engine, &data.resources);
+                    blocker.sites = Sites::load(&data);
+                }
+                Some(SITE_SETTINGS_UPDATED_MESSAGE) => {
+                    blocker.sites = Sites::load(&data);
+                    let parameters = message.property::<Option<glib::Variant>>("parameters");
+                    if let Some(https_only) = parameters.and_then(|p| p.get::<bool>()) {
+                        blocker.https_only = https_only;
+                    }
+                }
+                _ => return Some(false.to_value()),
+            }
+            Some(true.to_value())
+        }
+    });
+
+    extension.connect_local("page-created", false, move |values| {
+        let page = values[1].get::<glib::Object>().ok()?;
+        let blocker = blocker.clone();
+        let log_allowed = Rc::new(Cell::new(false));
+
+        page.connect_local("user-message-received", false, {
+            let log_allowed = log_allowed.clone();
+
+            move |values| {
+                let message = values[1].get::<glib::Object>().ok()?;
+                if message.property::<Option<String>>("name").as_deref()
+                    != Some(LOG_ALLOWED_MESSAGE)
+                {
+                    return Some(false.to_value());
+                }
+                let parameters = message.property::<Option<glib::Variant>>("parameters");
+                log_allowed.set(parameters.and_then(|p| p.get::<bool>()).unwrap_or(false));
+                Some(true.to_value())
+            }
+        });
+
+        page.connect_local("send-request", false, move |values| {
+            let page = values[0].get::<glib::Object>().ok()?;
+            let request = values[1].get::<glib::Object>().ok()?;
+            Some(on_send_request(&page, &request, &blocker.borrow(), log_allowed.get()).to_value())
+        });
+
+        None
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_local_hosts_are_local() {
+        let cases = [
+            ("http://localhost:8080/", true),
+            ("http://app.localhost/", true),
+            ("http://printer.local/", true),
+            ("http://127.0.0.1/", true),
+            ("http://127.1.2.3/", true),
+            ("http://10.0.0.1/", true),
+            ("http://172.16.0.1/", true),
+            ("http://172.31.255.255/", true),
+            ("http://192.168.1.1/", true),
+            ("http://169.254.0.1/", true),
+            ("http://[::1]/", true),
+            ("http://[fc00::1]/", true),
+            ("http://[fd12:3456::1]/", true),
+            ("http://[fe80::1]/", true),
+            ("http://[febf::1]/", true),
+            ("http://example.com/", false),
+            ("http://localhost.example.com/", false),
+            ("http://8.8.8.8/", false),
+            ("http://172.32.0.1/", false),
+            ("http://93.184.216.34/", false),
+            ("http://[2606:4700::1111]/", false),
+            ("http://[fec0::1]/", false),
+            ("http://[::ffff:8.8.8.8]/", false),
+        ];
+
+        for (url, local) in cases {
+            assert_eq!(is_local(&Url::parse(url).unwrap()), local, "url {:?}", url);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Guessing the adblock request type (`$script`, `$image`, ...) of a request.
+//!
+//! `send-request` doesn't say what the request is for, so the type comes from
+//! the scheme, the `Sec-Fetch-Dest` header where WebCore sets it, then the
+//! file extension and last the `Accept` header. The extension goes before
+//! `Accept` because most loads send `*/*`, and the types that do send their
+//! own (documents, stylesheets, images) rarely disagree with it.
+
+use url::Url;
+
+fn from_fetch_dest(dest: &str) -> Option<&'static str> {
+    let request_type = match dest {
+        "document" => "document",
+        "iframe" | "frame" => "subdocument",
+        "script" | "worker" | "sharedworker" | "serviceworker" | "audioworklet"
+        | "paintworklet" => "script",
+        "style" => "stylesheet",
+        "image" => "image",
+        "font" => "font",
+        "audio" | "video" | "track" => "media",
+        "object" | "embed" => "object",
+        "report" => "csp_report",
+        // `empty` is fetch, XHR, beacons and the like, which the URL and
+        // `Accept` tell apart better.
+        _ => return None,
+    };
+
+    Some(request_type)
+}
+
+fn from_extension(url: &Url) -> Option<&'static str> {
+    let path = url.path();
+    let extension = path.rsplit_once('.')?.1.to_ascii_lowercase();
+
+    let request_type = match extension.as_str() {
+        "js" | "mjs" => "script",
+        "css" => "stylesheet",
+        "png" | "jpg" | "jpeg" | "gif" | "webp" | "avif" | "svg" | "ico" | "bmp" => "image",
+        "woff" | "woff2" | "ttf" | "otf" | "eot" => "font",
+        "mp4" | "webm" | "mp3" | "ogg" | "m4a" | "m3u8" | "mpd" | "ts" => "media",
+        "html" | "htm" => "subdocument",
+        _ => return None,
+    };
+
+    Some(request_type)
+}
+
+fn from_accept(accept: &str) -> Option<&'static str> {
+    let request_type = if accept.starts_with("text/html") {
+        "subdocument"
+    } else if accept.starts_with("text/css") {
+        "stylesheet"
+    } else if accept.starts_with("image/") {
+        "image"
+    } else if accept.starts_with("video/") || accept.starts_with("audio/") {
+        "media"
+    } else if accept.starts_with("application/json") || accept.contains("text/plain") {
+        "xmlhttprequest"
+    } else {
+        return None;
+    };
+
+    Some(request_type)
+}
+
+/// `fetch_dest` and `accept` are the request's `Sec-Fetch-Dest` and `Accept`
+/// headers. `is_document` is set for the top-level document of the page.
+pub fn request_type(
+    url: &Url,
+    fetch_dest: Option<&str>,
+    accept: Option<&str>,
+    is_document: bool,
+) -> &'static str {
+    if matches!(url.scheme(), "ws" | "wss") {
+        return "websocket";
+    }
+
+    let request_type = fetch_dest
+        .and_then(from_fetch_dest)
+        .or_else(|| from_extension(url))
+        .or_else(|| accept.and_then(from_accept))
+        .unwrap_or("other");
+
+    if request_type == "subdocument" && is_document {
+        "document"
+    } else {
+        request_type
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTML: &str = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+    const IMAGES: &str = "image/webp,image/avif,image/png,image/svg+xml,image/*;q=0.8,*/*;q=0.5";
+
+    #[test]
+    fn guesses_types() {
+        let cases = [
+            // Sec-Fetch-Dest wins when it is set.
+            ("https://a.com/track", Some("script"), Some("*/*"), "script"),
+            ("https://a.com/pixel.gif", Some("script"), None, "script"),
+            (
+                "https://a.com/frame",
+                Some("iframe"),
+                Some(HTML),
+                "subdocument",
+            ),
+            ("https://a.com/font", Some("font"), Some("*/*"), "font"),
+            ("https://a.com/clip", Some("video"), Some("*/*"), "media"),
+            ("https://a.com/plugin", Some("embed"), None, "object"),
+            ("https://a.com/csp", Some("report"), None, "csp_report"),
+            // An empty destination falls back to the URL.
+            ("https://a.com/app.js", Some("empty"), Some("*/*"), "script"),
+            ("https://a.com/api", Some("empty"), Some("*/*"), "other"),
+            // Then the extension, ahead of a generic or odd Accept.
+            ("https://a.com/app.js", None, Some("*/*"), "script"),
+            ("https://a.com/app.MJS?v=2", None, None, "script"),
+            ("https://a.com/site.css", None, Some("*/*"), "stylesheet"),
+            ("https://a.com/logo.svg", None, Some("*/*"), "image"),
+            ("https://a.com/icon.png", None, Some(HTML), "image"),
+            ("https://a.com/f.woff2", None, Some("*/*"), "font"),
+            ("https://a.com/live.m3u8", None, Some("*/*"), "media"),
+            ("https://a.com/ad.html", None, Some("*/*"), "subdocument"),
+            // Then Accept.
+            ("https://a.com/frame", None, Some(HTML), "subdocument"),
+            (
+                "https://a.com/theme",
+                None,
+                Some("text/css,*/*;q=0.1"),
+                "stylesheet",
+            ),
+            ("https://a.com/pixel", None, Some(IMAGES), "image"),
+            ("https://a.com/stream", None, Some("video/*"), "media"),
+            (
+                "https://a.com/api",
+                None,
+                Some("application/json"),
+                "xmlhttprequest",
+            ),
+            ("https://a.com/api", None, Some("*/*"), "other"),
+            ("https://a.com/", None, None, "other"),
+            ("https://a.com/v1.2/data", None, None, "other"),
+            ("wss://a.com/socket", Some("empty"), None, "websocket"),
+            ("ws://a.com/socket.js", None, None, "websocket"),
+        ];
+
+        for (url, fetch_dest, accept, expected) in cases {
+            assert_eq!(
+                request_type(&Url::parse(url).unwrap(), fetch_dest, accept, false),
+                expected,
+                "url {:?}, Sec-Fetch-Dest {:?}, Accept {:?}",
+                url,
+                fetch_dest,
+                accept
+            );
+        }
+    }
+
+    #[test]
+    fn top_level_documents_are_documents() {
+        let page = Url::parse("https://a.com/article").unwrap();
+        assert_eq!(request_type(&page, None, Some(HTML), true), "document");
+        assert_eq!(
+            request_type(&page, Some("document"), Some(HTML), true),
+            "document"
+        );
+
+        let page = Url::parse("https://a.com/index.html").unwrap();
+        assert_eq!(request_type(&page, None, None, true), "document");
+        assert_eq!(request_type(&page, None, None, false), "subdocument");
+    }
+}